@@ -40,7 +40,6 @@ fn main() {
 	println!("cargo::rustc-env=RUSTC_VERSION={rustc_version}");
 
 	let mut enabled_features = std::env::vars()
-		.into_iter()
 		.filter_map(|(key, value)| {
 			if value != "1" || !key.starts_with("CARGO_FEATURE_") || key == "CARGO_FEATURE_DEFAULT" {
 				None
@@ -49,7 +48,7 @@ fn main() {
 			}
 		})
 		.collect::<Vec<String>>();
-	if std::env::var("CARGO_FEATURE_DEFAULT").unwrap_or_default().len() > 0 {
+	if !std::env::var("CARGO_FEATURE_DEFAULT").unwrap_or_default().is_empty() {
 		enabled_features.insert(0, "default".into());
 	}
 	if enabled_features.is_empty() {
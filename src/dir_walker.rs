@@ -0,0 +1,343 @@
+use std::{
+	collections::{HashSet, VecDeque},
+	fs::{self, FileType},
+	io::Error as IoError,
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{mpsc::sync_channel, Arc, Condvar, Mutex, PoisonError},
+	thread,
+};
+
+use ignore::gitignore::Gitignore;
+
+use crate::{
+	index_path::escaped,
+	progress::{verbose, warning},
+	scan_filter::ScanFilter,
+	worker_pool::panic_message,
+};
+
+/// What to do with symbolic links found while traversing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+	/// Pretend they don't exist
+	#[default]
+	Ignore,
+	/// Index the link itself, along with its target
+	Record,
+	/// Index whatever the link points to as if it were at the link's path
+	Follow,
+}
+impl FromStr for SymlinkPolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"ignore" => Ok(Self::Ignore),
+			"record" => Ok(Self::Record),
+			"follow" => Ok(Self::Follow),
+			_ => Err(format!(
+				"unknown symlink policy \"{s}\", expected one of: ignore, record, follow"
+			)),
+		}
+	}
+}
+
+/// Everything that came out of reading a single directory
+#[derive(Debug)]
+pub struct DirContents<T> {
+	pub path: PathBuf,
+	/// Everything in the directory which made it past the filters, sub-directories included
+	pub children: Vec<PathBuf>,
+	/// Whatever the visitor made of each child which isn't a directory
+	pub items: Vec<(PathBuf, T)>,
+	pub errors: Vec<(PathBuf, IoError)>,
+}
+
+#[derive(Debug)]
+struct DirJob {
+	path: PathBuf,
+	/// The ignore files of every directory leading to this one, starting from the root
+	ignore_files: Vec<Arc<Gitignore>>,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+	jobs: VecDeque<DirJob>,
+	/// Directories which are being read right now, and might still add more jobs
+	in_progress: usize,
+	/// Set if a worker panicked, so the others don't wait forever on a directory that will never finish
+	abandoned: bool,
+}
+
+/// Directories waiting to be read. Every worker takes from and adds to the same queue, the walk is over once it's empty
+/// and nobody is reading a directory anymore.
+#[derive(Debug, Default)]
+struct DirQueue {
+	state: Mutex<QueueState>,
+	changed: Condvar,
+}
+impl DirQueue {
+	fn push(&self, job: DirJob) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state.jobs.push_back(job);
+		self.changed.notify_one();
+	}
+	/// Blocks until there's a directory to read, or returns `None` if there's nothing left to do
+	fn pop(&self) -> Option<DirQueueGuard<'_>> {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		loop {
+			if state.abandoned {
+				return None;
+			}
+			if let Some(job) = state.jobs.pop_front() {
+				state.in_progress += 1;
+				return Some(DirQueueGuard { queue: self, job });
+			}
+			if state.in_progress == 0 {
+				return None;
+			}
+			state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+		}
+	}
+}
+
+/// Marks the directory as done once it's dropped, even if reading it panicked
+struct DirQueueGuard<'a> {
+	queue: &'a DirQueue,
+	job: DirJob,
+}
+impl Drop for DirQueueGuard<'_> {
+	fn drop(&mut self) {
+		let mut state = self.queue.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state.in_progress -= 1;
+		state.abandoned |= thread::panicking();
+		self.queue.changed.notify_all();
+	}
+}
+
+/// Walks a directory tree with several threads at once, each directory is read exactly once by whichever thread gets
+/// to it first.
+#[derive(Debug)]
+pub struct DirWalker {
+	root: PathBuf,
+	symlink_policy: SymlinkPolicy,
+	filter: ScanFilter,
+	/// (device, inode) of every directory that has been descended into. Symlinks to these aren't followed, otherwise
+	/// a link to a parent directory would have us going in circles forever.
+	visited_dirs: Mutex<HashSet<(u64, u64)>>,
+	/// Only set if we're staying on the same filesystem as the starting directory
+	root_device: Option<u64>,
+	/// Where the root really is, symlinks leading inside it aren't followed
+	real_root: PathBuf,
+}
+impl DirWalker {
+	pub fn new<P: AsRef<Path>>(
+		root: P,
+		symlink_policy: SymlinkPolicy,
+		one_file_system: bool,
+		filter: ScanFilter,
+	) -> Result<Self, IoError> {
+		let root = root.as_ref();
+		let metadata = fs::metadata(root)?;
+		Ok(Self {
+			root: root.to_path_buf(),
+			symlink_policy,
+			filter,
+			visited_dirs: Mutex::new(HashSet::from([(metadata.dev(), metadata.ino())])),
+			root_device: one_file_system.then_some(metadata.dev()),
+			real_root: fs::canonicalize(root)?,
+		})
+	}
+	/// Reads the root and everything under it using `jobs` threads. `visit` is called on the worker threads with
+	/// everything that isn't a directory, it can return `None` to leave something out. `on_dir` is called on this
+	/// thread with each directory once it has been read, if it returns an error the walk is stopped. All the threads
+	/// are done by the time this returns, if any of them panicked that's returned as an error.
+	pub fn walk<T: Send>(
+		&self,
+		jobs: usize,
+		visit: impl Fn(&Path, FileType) -> Result<Option<T>, IoError> + Sync,
+		mut on_dir: impl FnMut(DirContents<T>) -> anyhow::Result<()>,
+	) -> anyhow::Result<()> {
+		let queue = DirQueue::default();
+		queue.push(DirJob {
+			path: self.root.clone(),
+			ignore_files: self
+				.filter
+				.read_ignore_file(&self.root)
+				.map(Arc::new)
+				.into_iter()
+				.collect(),
+		});
+		let jobs = jobs.max(1);
+		let (result_send, result_recv) = sync_channel(jobs * 4);
+		thread::scope(|scope| {
+			let workers = (0..jobs)
+				.map(|thread_num| {
+					let result_send = result_send.clone();
+					let (queue, visit) = (&queue, &visit);
+					thread::Builder::new()
+						.name(format!("Dir walker #{thread_num}"))
+						.spawn_scoped(scope, move || {
+							while let Some(guard) = queue.pop() {
+								let contents = self.read_dir(&guard.job, queue, visit);
+								if result_send.send(contents).is_err() {
+									break;
+								}
+							}
+						})
+						.unwrap()
+				})
+				.collect::<Vec<_>>();
+			drop(result_send);
+			// Taking the receiver by value means it's dropped if on_dir fails, which stops the workers
+			let mut result = Ok(());
+			for contents in result_recv {
+				result = on_dir(contents);
+				if result.is_err() {
+					break;
+				}
+			}
+			// Every worker has to be joined, otherwise the scope would panic about the ones that weren't
+			for worker in workers {
+				if let Err(payload) = worker.join() {
+					result = Err(anyhow::anyhow!(
+						"directory walker thread panicked: {}",
+						panic_message(&*payload)
+					));
+				}
+			}
+			result
+		})
+	}
+	fn read_dir<T>(
+		&self,
+		job: &DirJob,
+		queue: &DirQueue,
+		visit: impl Fn(&Path, FileType) -> Result<Option<T>, IoError>,
+	) -> DirContents<T> {
+		let mut contents = DirContents {
+			path: job.path.clone(),
+			children: Vec::new(),
+			items: Vec::new(),
+			errors: Vec::new(),
+		};
+		let read_dir = match fs::read_dir(&job.path) {
+			Ok(read_dir) => read_dir,
+			Err(err) => {
+				// The directory itself still gets indexed, it just won't have anything in it
+				contents.errors.push((job.path.clone(), err));
+				return contents;
+			},
+		};
+		for dir_entry in read_dir {
+			let dir_entry = match dir_entry {
+				Ok(dir_entry) => dir_entry,
+				Err(err) => {
+					contents.errors.push((job.path.clone(), err));
+					break;
+				},
+			};
+			let path = dir_entry.path();
+			let file_type = match dir_entry.file_type() {
+				Ok(file_type) => self.effective_file_type(&path, file_type),
+				Err(err) => {
+					contents.errors.push((path, err));
+					continue;
+				},
+			};
+			if !self
+				.filter
+				.is_allowed(&path, file_type.is_dir(), job.ignore_files.iter().map(Arc::as_ref))
+			{
+				continue;
+			}
+			if file_type.is_dir() {
+				if !self.should_enter(&path) {
+					continue;
+				}
+				let mut ignore_files = job.ignore_files.clone();
+				ignore_files.extend(self.filter.read_ignore_file(&path).map(Arc::new));
+				queue.push(DirJob {
+					path: path.clone(),
+					ignore_files,
+				});
+				contents.children.push(path);
+				continue;
+			}
+			match visit(&path, file_type) {
+				Ok(Some(item)) => {
+					contents.children.push(path.clone());
+					contents.items.push((path, item));
+				},
+				Ok(None) => {},
+				Err(err) => contents.errors.push((path, err)),
+			}
+		}
+		contents
+	}
+	/// The type of what the entry points to if it's a symlink which should be followed. Symlinks which are dangling or
+	/// lead to a directory that has already been visited are left as-is. So are symlinks to directories inside the root,
+	/// those get walked under their real path, which has to win over any link to them. Otherwise their files would only
+	/// be indexed under the link, and never deleted through it. The directory is only marked as visited once it's
+	/// actually entered, in [DirWalker::should_enter].
+	fn effective_file_type(&self, path: &Path, file_type: FileType) -> FileType {
+		if !file_type.is_symlink() || self.symlink_policy != SymlinkPolicy::Follow {
+			return file_type;
+		}
+		match fs::metadata(path) {
+			Ok(metadata) if metadata.is_dir() => {
+				if fs::canonicalize(path).is_ok_and(|target| target.starts_with(&self.real_root)) {
+					verbose!(
+						"{}: not following symlink to a directory that's already being indexed",
+						escaped(path)
+					);
+					file_type
+				} else if !self
+					.visited_dirs
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.contains(&(metadata.dev(), metadata.ino()))
+				{
+					metadata.file_type()
+				} else {
+					warning!(
+						"{}: not following symlink to an already visited directory",
+						escaped(path)
+					);
+					file_type
+				}
+			},
+			Ok(metadata) => metadata.file_type(),
+			Err(_) => file_type,
+		}
+	}
+	/// Directories on other filesystems are skipped if we're staying on the same one. When following symlinks, so is
+	/// any directory that has already been entered, whether it was reached through a symlink or its real path.
+	/// Otherwise everything in it would be indexed twice, and each copy would look like a duplicate of the other.
+	fn should_enter(&self, path: &Path) -> bool {
+		if self.symlink_policy != SymlinkPolicy::Follow && self.root_device.is_none() {
+			return true;
+		}
+		if let Ok(metadata) = fs::metadata(path) {
+			if self
+				.root_device
+				.is_some_and(|root_device| root_device != metadata.dev())
+			{
+				warning!("{}: not crossing into another filesystem", escaped(path));
+				return false;
+			}
+			let first_visit = self
+				.visited_dirs
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.insert((metadata.dev(), metadata.ino()));
+			if !first_visit && self.symlink_policy == SymlinkPolicy::Follow {
+				warning!("{}: not entering an already visited directory again", escaped(path));
+				return false;
+			}
+		}
+		true
+	}
+}
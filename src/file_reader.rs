@@ -0,0 +1,223 @@
+use std::{
+	fs::File,
+	io::{Error as IoError, ErrorKind as IoErrorKind, Read},
+	os::fd::{AsRawFd, RawFd},
+	ptr, slice,
+	sync::LazyLock,
+};
+
+use crate::CLI_ARGS;
+
+static PAGE_SIZE: LazyLock<usize> = LazyLock::new(|| {
+	// SAFETY: sysconf doesn't touch any memory of ours
+	let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+	usize::try_from(page_size).unwrap_or(4096)
+});
+/// How much of a file is mapped at once to check which of its pages are cached
+const RESIDENCY_WINDOW: u64 = 1 << 30; // 1GiB
+
+/// Tells the kernel how the range is going to be used, a length of 0 means until the end of the file. It's only a
+/// hint, so failing doesn't matter.
+fn advise(fd: RawFd, offset: u64, len: u64, advice: libc::c_int) {
+	// SAFETY: only the file descriptor is passed, and it's kept open by whoever borrowed it to us
+	unsafe {
+		libc::posix_fadvise(fd, offset as libc::off_t, len as libc::off_t, advice);
+	}
+}
+
+/// Stops the kernel from reading ahead of whatever is read from the file, for when only a few blocks of it are needed.
+/// Otherwise it would bring in pages we never look at, and [CachedPages] wouldn't know to drop them.
+pub fn disable_readahead(file: &File) {
+	advise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_RANDOM);
+}
+
+/// Which pages of part of a file were already in the page cache before we read any of it, one bit each. Hashing every
+/// file once would otherwise push out everything that's actually being used on the machine, so the pages we brought
+/// in get dropped again, but anything that was cached already is left alone. Nothing is dropped if the cache is being
+/// kept.
+pub struct CachedPages {
+	first_page: u64,
+	page_count: u64,
+	bits: Vec<u64>,
+}
+impl CachedPages {
+	/// Checks `len` bytes from `offset`. Pages whose state couldn't be found out count as not cached.
+	pub fn check(file: &File, offset: u64, len: u64) -> Self {
+		let page_size = *PAGE_SIZE as u64;
+		let first_page = offset / page_size;
+		let page_count = (offset + len).div_ceil(page_size) - first_page;
+		let mut cached = Self {
+			first_page,
+			page_count,
+			bits: vec![0; page_count.div_ceil(64) as usize],
+		};
+		if CLI_ARGS.keep_cache {
+			return cached;
+		}
+		let window_pages = RESIDENCY_WINDOW / page_size;
+		let mut residency = Vec::new();
+		for window_start in (0..page_count).step_by(window_pages as usize) {
+			let window_len = (page_count - window_start).min(window_pages);
+			// Mapping pages past the end of the file is fine, as long as nothing reads them
+			let Ok(mapping) = Mapping::new(file, (first_page + window_start) * page_size, window_len * page_size)
+			else {
+				break;
+			};
+			residency.resize(window_len as usize, 0u8);
+			// SAFETY: the mapping is `window_len` pages long, and so is the vector the kernel writes into
+			if unsafe { libc::mincore(mapping.ptr, mapping.len, residency.as_mut_ptr()) } != 0 {
+				break;
+			}
+			for (page, _) in residency.iter().enumerate().filter(|(_, state)| *state & 1 != 0) {
+				let bit = window_start as usize + page;
+				cached.bits[bit / 64] |= 1 << (bit % 64);
+			}
+		}
+		cached
+	}
+	fn is_cached(&self, page: u64) -> bool {
+		let bit = (page - self.first_page) as usize;
+		self.bits[bit / 64] & (1 << (bit % 64)) != 0
+	}
+	/// Drops the pages which weren't cached before, out of the ones which are entirely within `start..end`. A page
+	/// that's cut off at the end is left for the next call, since the rest of it hasn't been read yet.
+	pub fn drop_new(&self, file: &File, start: u64, end: u64) {
+		if CLI_ARGS.keep_cache {
+			return;
+		}
+		let page_size = *PAGE_SIZE as u64;
+		let pages = (start / page_size).max(self.first_page)..(end / page_size).min(self.first_page + self.page_count);
+		// Runs of pages are dropped together, which is usually the whole range at once
+		let mut run_start = None;
+		for page in pages.start..=pages.end {
+			match (page < pages.end && !self.is_cached(page), run_start) {
+				(true, None) => run_start = Some(page),
+				(false, Some(first_page)) => {
+					advise(
+						file.as_raw_fd(),
+						first_page * page_size,
+						(page - first_page) * page_size,
+						libc::POSIX_FADV_DONTNEED,
+					);
+					run_start = None;
+				},
+				_ => {},
+			}
+		}
+	}
+	/// Drops every page which wasn't cached before, including anything the kernel read ahead
+	pub fn drop_all_new(&self, file: &File) {
+		let page_size = *PAGE_SIZE as u64;
+		self.drop_new(
+			file,
+			self.first_page * page_size,
+			(self.first_page + self.page_count) * page_size,
+		);
+	}
+}
+
+/// Reads the whole file in chunks of `--read-buffer-size`, handing each one to `on_chunk`. Whatever has been read is
+/// dropped from the page cache as it goes, unless it was already cached. Files which are at least `--mmap-min-size`
+/// are mapped instead of being read. Returns how much was read.
+pub fn read_chunks(file: &mut File, mut on_chunk: impl FnMut(&[u8]) -> Result<(), IoError>) -> Result<u64, IoError> {
+	let fd = file.as_raw_fd();
+	advise(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+	let buffer_size = (CLI_ARGS.read_buffer_size.0 as usize).max(*PAGE_SIZE);
+	let file_len = file.metadata()?.len();
+	// Has to be checked for the whole file up front, readahead brings pages in before their chunk is read
+	let cached = CachedPages::check(file, 0, file_len);
+	if file_len > 0 && CLI_ARGS.mmap_min_size.is_some_and(|min_size| file_len >= min_size.0) {
+		return read_chunks_mapped(file, file_len, buffer_size, &cached, on_chunk);
+	}
+	let mut buffer = vec![0u8; buffer_size];
+	let mut total_read = 0u64;
+	loop {
+		match file.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(read_amount) => {
+				on_chunk(&buffer[..read_amount])?;
+				// The page the previous chunk stopped in the middle of is finished now
+				cached.drop_new(file, total_read, total_read + read_amount as u64);
+				total_read += read_amount as u64;
+			},
+			Err(e) if matches!(e.kind(), IoErrorKind::Interrupted) => {},
+			Err(e) => return Err(e),
+		}
+	}
+	// Gets the last page, and whatever was read ahead past the end of what was hashed
+	cached.drop_all_new(file);
+	Ok(total_read)
+}
+
+/// A read-only view of part of a file, unmapped when it's dropped
+struct Mapping {
+	ptr: *mut libc::c_void,
+	len: usize,
+}
+impl Mapping {
+	/// `offset` has to be a multiple of the page size
+	fn new(file: &File, offset: u64, len: u64) -> Result<Self, IoError> {
+		let len = usize::try_from(len).map_err(IoError::other)?;
+		// SAFETY: a new mapping is created wherever the kernel likes, nothing existing is touched
+		let ptr = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				len,
+				libc::PROT_READ,
+				libc::MAP_SHARED,
+				file.as_raw_fd(),
+				offset as libc::off_t,
+			)
+		};
+		if ptr == libc::MAP_FAILED {
+			return Err(IoError::last_os_error());
+		}
+		Ok(Self { ptr, len })
+	}
+	fn advise(&self, offset: usize, len: usize, advice: libc::c_int) {
+		// SAFETY: the range is within the mapping, the advice that's used only affects what stays in memory
+		unsafe {
+			libc::madvise(self.ptr.add(offset), len, advice);
+		}
+	}
+	fn as_slice(&self) -> &[u8] {
+		// SAFETY: the mapping is readable and lives as long as self. It can't be written to through this process, but if
+		// the file is truncated by someone else, reading past the new end raises SIGBUS.
+		unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+	}
+}
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		// SAFETY: nothing borrowed from the mapping outlives it
+		unsafe {
+			libc::munmap(self.ptr, self.len);
+		}
+	}
+}
+
+fn read_chunks_mapped(
+	file: &File,
+	file_len: u64,
+	buffer_size: usize,
+	cached: &CachedPages,
+	mut on_chunk: impl FnMut(&[u8]) -> Result<(), IoError>,
+) -> Result<u64, IoError> {
+	let mapping = Mapping::new(file, 0, file_len)?;
+	mapping.advise(0, mapping.len, libc::MADV_SEQUENTIAL);
+	// madvise needs page aligned chunks, the mapping itself always starts on a page
+	let chunk_size = buffer_size.next_multiple_of(*PAGE_SIZE);
+	for (chunk_num, chunk) in mapping.as_slice().chunks(chunk_size).enumerate() {
+		on_chunk(chunk)?;
+		if !CLI_ARGS.keep_cache {
+			// Pages can't be dropped from the cache while they're still mapped. This only unmaps them from this process,
+			// it doesn't take anything out of the cache.
+			let offset = chunk_num * chunk_size;
+			mapping.advise(offset, chunk.len(), libc::MADV_DONTNEED);
+			cached.drop_new(file, offset as u64, (offset + chunk.len()) as u64);
+		}
+	}
+	// Readahead which was still in flight when its chunk was dropped can leave pages behind
+	drop(mapping);
+	cached.drop_all_new(file);
+	Ok(file_len)
+}
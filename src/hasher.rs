@@ -0,0 +1,106 @@
+use std::{fmt, str::FromStr};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::Digest;
+
+/// Something which can digest a file's contents piece by piece.
+pub trait FileHasher {
+	fn update(&mut self, data: &[u8]);
+	fn finalize(self: Box<Self>) -> Box<[u8]>;
+}
+
+impl FileHasher for blake3::Hasher {
+	fn update(&mut self, data: &[u8]) {
+		blake3::Hasher::update(self, data);
+	}
+	fn finalize(self: Box<Self>) -> Box<[u8]> {
+		Box::from(blake3::Hasher::finalize(&self).as_bytes().as_slice())
+	}
+}
+
+impl FileHasher for xxhash_rust::xxh3::Xxh3 {
+	fn update(&mut self, data: &[u8]) {
+		xxhash_rust::xxh3::Xxh3::update(self, data);
+	}
+	fn finalize(self: Box<Self>) -> Box<[u8]> {
+		Box::from(self.digest128().to_be_bytes().as_slice())
+	}
+}
+
+impl FileHasher for sha2::Sha256 {
+	fn update(&mut self, data: &[u8]) {
+		Digest::update(self, data);
+	}
+	fn finalize(self: Box<Self>) -> Box<[u8]> {
+		Box::from(Digest::finalize(*self).as_slice())
+	}
+}
+
+/// Hashes everything with both SHA-256 and SHA-512, the digest is both of them concatenated.
+#[derive(Debug, Default)]
+pub struct Sha256Sha512Hasher {
+	hash_256: sha2::Sha256,
+	hash_512: sha2::Sha512,
+}
+impl FileHasher for Sha256Sha512Hasher {
+	fn update(&mut self, data: &[u8]) {
+		Digest::update(&mut self.hash_256, data);
+		Digest::update(&mut self.hash_512, data);
+	}
+	fn finalize(self: Box<Self>) -> Box<[u8]> {
+		let mut digest = Vec::with_capacity(32 + 64);
+		digest.extend_from_slice(&Digest::finalize(self.hash_256));
+		digest.extend_from_slice(&Digest::finalize(self.hash_512));
+		digest.into()
+	}
+}
+
+/// Digests made with different algorithms can't be compared, so the index remembers which one it was made with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum HashAlgorithm {
+	Blake3 = 0,
+	Xxh3 = 1,
+	Sha256 = 2,
+	#[default]
+	Sha256Sha512 = 3,
+}
+impl HashAlgorithm {
+	pub fn new_hasher(self) -> Box<dyn FileHasher> {
+		match self {
+			Self::Blake3 => Box::new(blake3::Hasher::new()),
+			Self::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+			Self::Sha256 => Box::new(sha2::Sha256::new()),
+			Self::Sha256Sha512 => Box::new(Sha256Sha512Hasher::default()),
+		}
+	}
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Blake3 => "blake3",
+			Self::Xxh3 => "xxh3",
+			Self::Sha256 => "sha256",
+			Self::Sha256Sha512 => "sha256+sha512",
+		}
+	}
+}
+impl fmt::Display for HashAlgorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+impl FromStr for HashAlgorithm {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"blake3" => Ok(Self::Blake3),
+			"xxh3" | "xxh3-128" => Ok(Self::Xxh3),
+			"sha256" => Ok(Self::Sha256),
+			"sha256+sha512" | "dual" => Ok(Self::Sha256Sha512),
+			_ => Err(format!(
+				"unknown hash algorithm \"{s}\", expected one of: blake3, xxh3, sha256, sha256+sha512"
+			)),
+		}
+	}
+}
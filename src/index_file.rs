@@ -0,0 +1,546 @@
+use std::{
+	ffi::OsString,
+	fmt,
+	fs::{self, File},
+	io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Arc,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use const_format::concatcp;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+	hasher::HashAlgorithm,
+	index_path::{escaped, IndexPath},
+	indexer::{FileHash, FileIndex, FileIndexItem, FileMeta},
+	progress::{info, verbose},
+};
+
+/// Every index file starts with this, anything else isn't one of ours. Files from before the format was versioned
+/// don't have it either.
+const MAGIC: [u8; 8] = *b"FDUPEIDX";
+/// Has to go up whenever the layout of [FileIndex] or anything in it changes, with a way to read the old version
+/// added to [read_index].
+///
+/// 0: Unversioned, no magic or header, just the index fdupes 0.1.1 wrote. See [read_unversioned_index].
+/// 1: The header, then the [FileIndex], compressed if [IndexHeader::compression] says so, then an xxh3 checksum of
+///    everything before it
+pub const FORMAT_VERSION: u32 = 1;
+const TOOL_VERSION: &str = concatcp!(env!("CARGO_PKG_NAME"), " ", env!("BUILD_VERSION"));
+/// zstd's own default, anything higher is a lot slower for not much smaller indexes
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the [FileIndex] after the header is stored. The header itself is never compressed, so it can always be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum Compression {
+	#[default]
+	None = 0,
+	Zstd = 1,
+}
+impl Compression {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::None => "none",
+			Self::Zstd => "zstd",
+		}
+	}
+}
+impl fmt::Display for Compression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+impl FromStr for Compression {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"none" => Ok(Self::None),
+			"zstd" => Ok(Self::Zstd),
+			_ => Err(format!("unknown compression \"{s}\", expected one of: none, zstd")),
+		}
+	}
+}
+
+/// The parts of the header which aren't taken from the index itself, they're carried over every time it's saved
+#[derive(Debug, Clone, Copy)]
+pub struct SaveOptions {
+	/// Seconds since the Unix epoch
+	pub created_at: u64,
+	pub compression: Compression,
+}
+
+/// Describes the index that follows it, so it can be told apart from other files and older versions can be handled.
+/// Only the format version has to stay where it is, everything after it can change along with the format.
+#[derive(Debug, Clone, BorshSerialize)]
+pub struct IndexHeader {
+	/// The version the index was read as, it's always written with the current one
+	pub format_version: u32,
+	pub hash_algorithm: HashAlgorithm,
+	/// Seconds since the Unix epoch when the index was first created, rescans don't change this
+	pub created_at: u64,
+	/// Which version of fdupes last wrote the index
+	pub tool_version: String,
+	pub roots: Vec<IndexPath>,
+	pub compression: Compression,
+}
+impl IndexHeader {
+	pub fn new(index: &FileIndex, options: SaveOptions) -> Self {
+		Self {
+			format_version: FORMAT_VERSION,
+			hash_algorithm: index.hash_algorithm,
+			created_at: options.created_at,
+			tool_version: TOOL_VERSION.into(),
+			roots: index.root_paths(),
+			compression: options.compression,
+		}
+	}
+	pub fn save_options(&self) -> SaveOptions {
+		SaveOptions {
+			created_at: self.created_at,
+			compression: self.compression,
+		}
+	}
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time_now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|since_epoch| since_epoch.as_secs())
+		.unwrap_or_default()
+}
+
+/// Hashes everything that's written through it, so the checksum can be put at the end
+struct ChecksumWriter<W> {
+	inner: W,
+	hasher: Xxh3,
+}
+impl<W: Write> Write for ChecksumWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.hasher.update(&buf[..written]);
+		Ok(written)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Hashes everything that's read through it, to compare with the checksum at the end. Only what's actually consumed is
+/// hashed, so the decompressor can look ahead without the checksum itself ending up in the hash.
+struct ChecksumReader<R> {
+	inner: R,
+	hasher: Xxh3,
+}
+impl<R: Read> Read for ChecksumReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		self.hasher.update(&buf[..read]);
+		Ok(read)
+	}
+}
+impl<R: BufRead> BufRead for ChecksumReader<R> {
+	fn fill_buf(&mut self) -> io::Result<&[u8]> {
+		self.inner.fill_buf()
+	}
+	fn consume(&mut self, amount: usize) {
+		// This is the same buffer the caller just looked at, it doesn't read anything new
+		if let Ok(buf) = self.inner.fill_buf() {
+			self.hasher.update(&buf[..amount.min(buf.len())]);
+		}
+		self.inner.consume(amount);
+	}
+}
+
+/// Where the index is written before it's moved over the real one. It's in the same directory, since renaming only
+/// replaces the file in one go if it stays on the same filesystem.
+fn temp_path(path: &Path) -> PathBuf {
+	let mut file_name = OsString::from(".");
+	file_name.push(path.file_name().unwrap_or_default());
+	file_name.push(".tmp");
+	path.with_file_name(file_name)
+}
+
+/// Makes sure the index can be saved, before spending hours scanning only to find out it can't
+pub fn check_writable(path: &Path) -> anyhow::Result<()> {
+	let temp_path = temp_path(path);
+	File::create(&temp_path).map_err(|err| anyhow::anyhow!("{} can't be written to: {err}", escaped(path)))?;
+	fs::remove_file(&temp_path)?;
+	Ok(())
+}
+
+/// Saves the index to `path` without ever leaving a half written file there. It's written to a temporary file next
+/// to it, synced to disk and then renamed over the old one, so a crash leaves either the old index or the new one.
+pub fn save_index(path: &Path, index: &FileIndex, options: SaveOptions) -> anyhow::Result<()> {
+	info!("Writing index file...");
+	let temp_path = temp_path(path);
+	let result = write_temp_file(&temp_path, path, index, options).and_then(|()| {
+		fs::rename(&temp_path, path)?;
+		// The rename itself only sticks once the directory is synced too
+		let dir = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+		File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+		Ok(())
+	});
+	if result.is_err() {
+		let _ = fs::remove_file(&temp_path);
+	}
+	result
+}
+
+/// Everything is streamed straight to the file, the index is never serialized into memory first
+fn write_temp_file(temp_path: &Path, path: &Path, index: &FileIndex, options: SaveOptions) -> anyhow::Result<()> {
+	let temp_file = File::create(temp_path)?;
+	// Whoever could read the old index should be able to read the new one
+	if let Ok(metadata) = fs::metadata(path) {
+		temp_file.set_permissions(metadata.permissions())?;
+	}
+	// Buffered before it's hashed, so the hasher gets big chunks instead of every little field
+	let mut writer = BufWriter::new(ChecksumWriter {
+		inner: temp_file,
+		hasher: Xxh3::new(),
+	});
+	writer.write_all(&MAGIC)?;
+	borsh::to_writer(&mut writer, &IndexHeader::new(index, options))?;
+	let writer = writer.into_inner().map_err(|err| err.into_error())?;
+	let mut writer = match options.compression {
+		Compression::None => {
+			let mut writer = BufWriter::new(writer);
+			borsh::to_writer(&mut writer, index)?;
+			writer.into_inner().map_err(|err| err.into_error())?
+		},
+		Compression::Zstd => {
+			let mut writer = BufWriter::new(zstd::Encoder::new(writer, ZSTD_LEVEL)?);
+			borsh::to_writer(&mut writer, index)?;
+			writer.into_inner().map_err(|err| err.into_error())?.finish()?
+		},
+	};
+	let checksum = writer.hasher.digest();
+	writer.inner.write_all(&checksum.to_le_bytes())?;
+	info!("Saving file...");
+	writer.inner.sync_all()?;
+	Ok(())
+}
+
+/// Reads an index, upgrading it to the current format if it's from an older version of fdupes. The header says which
+/// version it was read as, anything older than [FORMAT_VERSION] should be written back out so it's only upgraded once.
+pub fn read_index(index_file: &mut File) -> anyhow::Result<(IndexHeader, FileIndex)> {
+	let mut magic = [0u8; MAGIC.len()];
+	let has_magic = index_file.read_exact(&mut magic).is_ok() && magic == MAGIC;
+	if !has_magic {
+		return read_unversioned_index(index_file);
+	}
+	let mut hasher = Xxh3::new();
+	hasher.update(&magic);
+	let mut reader = ChecksumReader {
+		inner: BufReader::new(index_file),
+		hasher,
+	};
+	let format_version = u32::deserialize_reader(&mut reader)?;
+	if format_version > FORMAT_VERSION {
+		anyhow::bail!(
+			"The index was written by a newer version of fdupes (format version {format_version}, this one only \
+			 understands up to {FORMAT_VERSION}). Update fdupes, or delete the index and scan again."
+		);
+	}
+	// Older versions get their own arm here once there are any, each one upgrading to the next
+	let header = match format_version {
+		FORMAT_VERSION => IndexHeader {
+			format_version,
+			hash_algorithm: HashAlgorithm::deserialize_reader(&mut reader)?,
+			created_at: u64::deserialize_reader(&mut reader)?,
+			tool_version: String::deserialize_reader(&mut reader)?,
+			roots: Vec::deserialize_reader(&mut reader)?,
+			compression: Compression::deserialize_reader(&mut reader)?,
+		},
+		_ => anyhow::bail!(
+			"Format version {format_version} of the index isn't supported anymore, delete it and scan again."
+		),
+	};
+	verbose!(
+		"index format version {format_version}, written by {}, created at {} (Unix time), compression: {}",
+		header.tool_version,
+		header.created_at,
+		header.compression
+	);
+	let damaged = |reason: &dyn fmt::Display| {
+		anyhow::anyhow!("The index is damaged and can't be read ({reason}), delete it and scan again.")
+	};
+	let index = match header.compression {
+		Compression::None => FileIndex::deserialize_reader(&mut reader).map_err(|err| damaged(&err))?,
+		Compression::Zstd => {
+			// Only the one frame is read, the checksum comes straight after it
+			let mut decoder = zstd::Decoder::with_buffer(&mut reader)?.single_frame();
+			let index = FileIndex::deserialize_reader(&mut decoder).map_err(|err| damaged(&err))?;
+			// Reading the rest of the frame makes sure nothing was left over in it
+			if decoder.read(&mut [0u8]).map_err(|err| damaged(&err))? != 0 {
+				return Err(damaged(&"there's something after the end of it"));
+			}
+			index
+		},
+	};
+	let checksum = reader.hasher.digest();
+	let mut reader = reader.inner;
+	let stored_checksum = u64::from_le_bytes(<[u8; 8]>::deserialize_reader(&mut reader).map_err(|err| damaged(&err))?);
+	if stored_checksum != checksum {
+		return Err(damaged(&"the checksum doesn't match"));
+	}
+	if reader.read(&mut [0u8])? != 0 {
+		return Err(damaged(&"there's something after the end of it"));
+	}
+	Ok((header, index))
+}
+
+/// What fdupes 0.1.1 stored for each file, before there was any choice of hash
+#[derive(BorshDeserialize)]
+struct UnversionedFileHash {
+	_file_len: u64,
+	_digest_256: [u8; 32],
+	_digest_512: [u8; 64],
+}
+
+#[derive(BorshDeserialize)]
+enum UnversionedItem {
+	File { _hash: UnversionedFileHash },
+	Folder { contents: Vec<Arc<str>> },
+}
+
+/// The whole index file of fdupes 0.1.1. Every file that was found was hashed in full, and paths were only kept as
+/// (lossy) UTF-8.
+#[derive(BorshDeserialize)]
+struct UnversionedIndex {
+	// Maps and sets are stored the same way as vectors of what's in them. Every file is in paths_to_items too, this is
+	// rebuilt from that.
+	_hash_to_paths: Vec<(UnversionedFileHash, Vec<Arc<str>>)>,
+	paths_to_items: Vec<(Arc<str>, UnversionedItem)>,
+}
+
+/// Files from before there was a header are a bare index in the layout fdupes 0.1.1 wrote. It didn't keep any metadata,
+/// and saving it again didn't change its modification time in any useful way, so there's no telling whether a file
+/// changed since it was hashed. Every file is brought in with only its size, and the index is marked incomplete so
+/// the rescan hashes them again. 0.1.1 also never truncated the file when it saved over it, so whatever comes after the
+/// index is left over from an older, longer one and is ignored.
+fn read_unversioned_index(index_file: &mut File) -> anyhow::Result<(IndexHeader, FileIndex)> {
+	index_file.rewind()?;
+	let unversioned = UnversionedIndex::deserialize_reader(&mut BufReader::new(&mut *index_file)).map_err(|_| {
+		anyhow::anyhow!("This isn't an fdupes index, or it was made by a version of fdupes this one can't read.")
+	})?;
+	info!("The index is from fdupes 0.1.1, upgrading it");
+	let created_at = index_file
+		.metadata()
+		.and_then(|metadata| metadata.modified())
+		.ok()
+		.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+		.map_or_else(unix_time_now, |since_epoch| since_epoch.as_secs());
+	let mut index = FileIndex {
+		hash_algorithm: HashAlgorithm::Sha256Sha512,
+		complete: false,
+		..Default::default()
+	};
+	for (path, item) in unversioned.paths_to_items {
+		let path = IndexPath::from(&*path);
+		let item = match item {
+			UnversionedItem::Folder { contents } => FileIndexItem::Folder {
+				contents: contents.iter().map(|child| IndexPath::from(&**child)).collect(),
+			},
+			UnversionedItem::File { .. } => {
+				// Anything that's gone is dropped by the rescan
+				let Ok(metadata) = fs::metadata(&path) else {
+					continue;
+				};
+				FileIndexItem::File {
+					hash: FileHash::from_len(metadata.len()),
+					meta: FileMeta::from(&metadata),
+				}
+			},
+		};
+		index.paths_to_items.insert(path, item);
+	}
+	let header = IndexHeader {
+		format_version: 0,
+		..IndexHeader::new(
+			&index,
+			SaveOptions {
+				created_at,
+				compression: Compression::None,
+			},
+		)
+	};
+	Ok((header, index))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{env, process};
+
+	use super::*;
+	use crate::indexer::FileDigest;
+
+	/// A directory of its own for each test, removed once it's done
+	struct TestDir(PathBuf);
+	impl TestDir {
+		fn new(name: &str) -> Self {
+			let path = env::temp_dir().join(format!("fdupes-test-{}-{name}", process::id()));
+			let _ = fs::remove_dir_all(&path);
+			fs::create_dir_all(&path).unwrap();
+			Self(path)
+		}
+	}
+	impl Drop for TestDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	/// A folder of files which are all duplicates of each other, so it compresses well
+	fn sample_index(file_count: usize) -> FileIndex {
+		let mut index = FileIndex {
+			hash_algorithm: HashAlgorithm::Xxh3,
+			complete: true,
+			..Default::default()
+		};
+		let hash = FileHash {
+			file_len: 4096,
+			digest: FileDigest::Full {
+				digest: Box::new([0xab; 16]),
+			},
+		};
+		let files = (0..file_count)
+			.map(|i| IndexPath::from(&format!("/data/file {i}")))
+			.collect::<Vec<_>>();
+		for (inode, path) in files.iter().enumerate() {
+			let meta = FileMeta {
+				mtime: 1_700_000_000,
+				mtime_nsec: 0,
+				ctime: 1_700_000_000,
+				ctime_nsec: 0,
+				device: 1,
+				inode: inode as u64,
+			};
+			index.paths_to_items.insert(
+				path.clone(),
+				FileIndexItem::File {
+					hash: hash.clone(),
+					meta,
+				},
+			);
+		}
+		index.hash_to_paths.insert(hash, files.iter().cloned().collect());
+		index
+			.paths_to_items
+			.insert("/data".into(), FileIndexItem::Folder { contents: files });
+		index.paths_to_items.insert(
+			":root".into(),
+			FileIndexItem::Folder {
+				contents: vec!["/data".into()],
+			},
+		);
+		index
+	}
+
+	fn options(compression: Compression) -> SaveOptions {
+		SaveOptions {
+			created_at: 1_700_000_123,
+			compression,
+		}
+	}
+
+	/// Saves the sample index and returns what ended up on disk
+	fn saved_bytes(dir: &TestDir, file_count: usize, compression: Compression) -> Vec<u8> {
+		let path = dir.0.join("index");
+		save_index(&path, &sample_index(file_count), options(compression)).unwrap();
+		fs::read(path).unwrap()
+	}
+
+	fn read_bytes(dir: &TestDir, bytes: &[u8]) -> anyhow::Result<(IndexHeader, FileIndex)> {
+		let path = dir.0.join("modified");
+		fs::write(&path, bytes).unwrap();
+		read_index(&mut File::open(path).unwrap())
+	}
+
+	#[test]
+	fn save_then_load() {
+		let dir = TestDir::new("save-then-load");
+		for compression in [Compression::None, Compression::Zstd] {
+			let path = dir.0.join(compression.name());
+			let index = sample_index(100);
+			save_index(&path, &index, options(compression)).unwrap();
+			let (header, loaded) = read_index(&mut File::open(&path).unwrap()).unwrap();
+			assert_eq!(header.format_version, FORMAT_VERSION);
+			assert_eq!(header.hash_algorithm, HashAlgorithm::Xxh3);
+			assert_eq!(header.created_at, 1_700_000_123);
+			assert_eq!(header.compression, compression);
+			assert_eq!(header.roots, vec![IndexPath::from("/data")]);
+			assert_eq!(borsh::to_vec(&loaded).unwrap(), borsh::to_vec(&index).unwrap());
+			assert!(!temp_path(&path).exists());
+		}
+	}
+
+	#[test]
+	fn zstd_is_smaller() {
+		let dir = TestDir::new("zstd-is-smaller");
+		assert!(saved_bytes(&dir, 100, Compression::Zstd).len() < saved_bytes(&dir, 100, Compression::None).len());
+	}
+
+	#[test]
+	fn rejects_flipped_bytes() {
+		let dir = TestDir::new("rejects-flipped-bytes");
+		for compression in [Compression::None, Compression::Zstd] {
+			// Every byte gets a go, so this is kept small
+			let bytes = saved_bytes(&dir, 3, compression);
+			for offset in 0..bytes.len() {
+				let mut damaged = bytes.clone();
+				damaged[offset] ^= 0x01;
+				assert!(
+					read_bytes(&dir, &damaged).is_err(),
+					"{compression}: flipping byte {offset} wasn't noticed"
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_truncated_files() {
+		let dir = TestDir::new("rejects-truncated-files");
+		for compression in [Compression::None, Compression::Zstd] {
+			// Every byte gets a go, so this is kept small
+			let bytes = saved_bytes(&dir, 3, compression);
+			for len in 1..bytes.len() {
+				assert!(
+					read_bytes(&dir, &bytes[..len]).is_err(),
+					"{compression}: cutting it off at {len} bytes wasn't noticed"
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_trailing_garbage() {
+		let dir = TestDir::new("rejects-trailing-garbage");
+		for compression in [Compression::None, Compression::Zstd] {
+			let mut bytes = saved_bytes(&dir, 3, compression);
+			bytes.extend_from_slice(b"garbage");
+			let err = read_bytes(&dir, &bytes).unwrap_err();
+			assert!(
+				err.to_string().contains("something after the end"),
+				"{compression}: {err}"
+			);
+		}
+	}
+
+	#[test]
+	fn rejects_newer_format_versions() {
+		let dir = TestDir::new("rejects-newer-format-versions");
+		let mut bytes = saved_bytes(&dir, 3, Compression::None);
+		// The format version comes straight after the magic
+		bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+		let err = read_bytes(&dir, &bytes).unwrap_err();
+		assert!(err.to_string().contains("newer version of fdupes"), "{err}");
+	}
+}
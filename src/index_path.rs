@@ -0,0 +1,112 @@
+use std::{
+	borrow::Borrow,
+	ffi::OsStr,
+	fmt::{self, Write},
+	os::unix::ffi::{OsStrExt, OsStringExt},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Arc,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// A path stored as the exact bytes the OS gave us. Filenames don't have to be valid UTF-8, and a lossy conversion
+/// would leave us deleting a path which doesn't exist, or worse, a different file.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, BorshDeserialize, BorshSerialize)]
+pub struct IndexPath(Arc<[u8]>);
+impl IndexPath {
+	pub fn as_path(&self) -> &Path {
+		Path::new(OsStr::from_bytes(&self.0))
+	}
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+}
+impl<P: AsRef<Path> + ?Sized> From<&P> for IndexPath {
+	fn from(path: &P) -> Self {
+		Self(Arc::from(path.as_ref().as_os_str().as_bytes()))
+	}
+}
+impl From<PathBuf> for IndexPath {
+	fn from(path: PathBuf) -> Self {
+		Self(Arc::from(path.into_os_string().into_vec()))
+	}
+}
+impl AsRef<Path> for IndexPath {
+	fn as_ref(&self) -> &Path {
+		self.as_path()
+	}
+}
+/// `OsStr` is ordered by its bytes on unix, so maps keyed by [IndexPath] can be looked up with a plain path
+impl Borrow<OsStr> for IndexPath {
+	fn borrow(&self) -> &OsStr {
+		OsStr::from_bytes(&self.0)
+	}
+}
+impl fmt::Display for IndexPath {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		escaped(self.as_path()).fmt(f)
+	}
+}
+impl fmt::Debug for IndexPath {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "\"{self}\"")
+	}
+}
+/// Undoes the escaping done when displaying, so a path can be typed in exactly as it was printed
+impl FromStr for IndexPath {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut bytes = Vec::with_capacity(s.len());
+		let mut rest = s.as_bytes();
+		while let Some((&byte, tail)) = rest.split_first() {
+			rest = tail;
+			if byte != b'\\' {
+				bytes.push(byte);
+				continue;
+			}
+			match rest {
+				[b'\\', tail @ ..] => {
+					bytes.push(b'\\');
+					rest = tail;
+				},
+				[b'x', high, low, tail @ ..] if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+					let hex = [*high, *low];
+					// Both are ASCII hex digits, so neither of these can fail
+					bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+					rest = tail;
+				},
+				_ => bytes.push(b'\\'),
+			}
+		}
+		Ok(Self(bytes.into()))
+	}
+}
+
+/// Displays a path with anything that isn't printable UTF-8 written as "\xNN", and backslashes doubled up
+pub struct Escaped<'a>(&'a [u8]);
+pub fn escaped(path: &Path) -> Escaped<'_> {
+	Escaped(path.as_os_str().as_bytes())
+}
+impl fmt::Display for Escaped<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for chunk in self.0.utf8_chunks() {
+			for c in chunk.valid().chars() {
+				if c == '\\' {
+					f.write_str("\\\\")?;
+				} else if c.is_control() {
+					for byte in c.encode_utf8(&mut [0u8; 4]).bytes() {
+						write!(f, "\\x{byte:02x}")?;
+					}
+				} else {
+					f.write_char(c)?;
+				}
+			}
+			for byte in chunk.invalid() {
+				write!(f, "\\x{byte:02x}")?;
+			}
+		}
+		Ok(())
+	}
+}
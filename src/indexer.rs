@@ -1,69 +1,359 @@
 use std::{
 	collections::{BTreeMap, BTreeSet},
-	fs::{self, read_dir, DirEntry, File, FileType},
-	io::{Error as IoError, ErrorKind as IoErrorKind, Read},
-	mem,
+	ffi::OsStr,
+	fs::{self, File, FileType, Metadata},
+	io::{Error as IoError, ErrorKind as IoErrorKind, Read, Seek, SeekFrom},
+	os::unix::{ffi::OsStrExt, fs::MetadataExt},
 	path::Path,
-	sync::Arc,
+	time::{Duration, Instant},
 };
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use sha2::Digest;
 
 use crate::{
-	deep_readdir::DeepReadDir, file_closer::deferred_file_drop, multi_thread_iter::multi_thread_map_iter, CLI_ARGS,
+	dir_walker::{DirWalker, SymlinkPolicy},
+	file_closer::deferred_file_drop,
+	file_reader::{disable_readahead, read_chunks, CachedPages},
+	hasher::HashAlgorithm,
+	index_path::{escaped, IndexPath},
+	interrupt,
+	mounts::{backing_device, dev_major_minor, is_rotational, VolumeInfo},
+	progress::{self, info, verbose, warning, ProgressReporter, Stage},
+	scan_error::{ScanError, ScanErrorKind},
+	scan_filter::{EmptyFilePolicy, ScanFilter, SizeRange},
+	throttle::throttle,
+	verify::{verify_identical, verify_separate_links},
+	worker_pool::parallel_map,
+	CLI_ARGS,
 };
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, BorshDeserialize, BorshSerialize)]
+/// How much of the file was read before it was determined to be unique. Files are only compared more thoroughly if
+/// the previous level found another file which matched.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, BorshDeserialize, BorshSerialize)]
+pub enum FileDigest {
+	/// Nothing was read, no other file has the same length.
+	Size,
+	/// Only the first and last [PARTIAL_HASH_BLOCK_SIZE] bytes were hashed. Files with this digest are only probable
+	/// duplicates of each other.
+	Partial { digest: Box<[u8]> },
+	/// The entire file was hashed.
+	Full { digest: Box<[u8]> },
+	/// The file kept changing while it was being read, so whatever was hashed doesn't match any real state of it.
+	/// These are never considered duplicates of anything, so they're never deleted.
+	Unstable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, BorshDeserialize, BorshSerialize)]
 pub struct FileHash {
 	pub file_len: u64,
-	pub digest_256: [u8; 32],
-	pub digest_512: [u8; 64],
+	pub digest: FileDigest,
 }
+/// Size of the blocks at the start and end of a file that are hashed for a partial digest
+pub const PARTIAL_HASH_BLOCK_SIZE: u64 = 16384; // 16KiB
 impl FileHash {
-	pub fn from_file(mut file: File) -> Result<Self, IoError> {
-		let mut hash_256 = sha2::Sha256::new();
-		let mut hash_512 = sha2::Sha512::new();
-		let mut file_buf = [0u8; 524288]; // 512KiB
-		let mut total_read = 0u64;
-		loop {
-			match file.read(&mut file_buf) {
-				Ok(0) => break,
-				Ok(read_amount) => {
-					let read_buf = &file_buf[0..read_amount];
-					hash_256.update(read_buf);
-					hash_512.update(read_buf);
-					total_read += read_amount as u64;
-				},
-				Err(e) if matches!(e.kind(), IoErrorKind::Interrupted) => {},
-				Err(e) => return Err(e),
+	pub fn from_len(file_len: u64) -> Self {
+		Self {
+			file_len,
+			digest: FileDigest::Size,
+		}
+	}
+	pub fn is_confirmed(&self) -> bool {
+		matches!(self.digest, FileDigest::Full { .. })
+	}
+	pub fn is_unstable(&self) -> bool {
+		self.digest == FileDigest::Unstable
+	}
+	/// Gets an [FileDigest::Unstable] hash if the file's size or modification time changed while it was being read.
+	/// `file_len` is how much was actually read.
+	fn check_stable(self, before: FileState, file: &File) -> Result<Self, IoError> {
+		if before != FileState::from(&file.metadata()?) || self.file_len != before.len {
+			return Ok(Self {
+				file_len: self.file_len,
+				digest: FileDigest::Unstable,
+			});
+		}
+		Ok(self)
+	}
+	pub fn from_file(mut file: File, algorithm: HashAlgorithm) -> Result<Self, IoError> {
+		let before = FileState::from(&file.metadata()?);
+		let mut hasher = algorithm.new_hasher();
+		let total_read = read_chunks(&mut file, |chunk| {
+			// Big files can take a long time to read, especially when throttled, so this doesn't wait for them
+			if interrupt::is_interrupted() {
+				return Err(IoError::new(IoErrorKind::Interrupted, "the scan was interrupted"));
 			}
+			hasher.update(chunk);
+			progress::add_bytes_done(chunk.len() as u64);
+			throttle(chunk.len() as u64);
+			Ok(())
+		})?;
+		let hash = Self {
+			file_len: total_read,
+			digest: FileDigest::Full {
+				digest: hasher.finalize(),
+			},
 		}
+		.check_stable(before, &file)?;
 		deferred_file_drop(file);
-		Ok(Self {
-			file_len: total_read,
-			digest_256: hash_256.finalize().into(),
-			digest_512: hash_512.finalize().into(),
-		})
+		Ok(hash)
 		//file.read()
 	}
+	/// Only hashes the first and last [PARTIAL_HASH_BLOCK_SIZE] bytes of the file. Files which are smaller than two
+	/// blocks should just be hashed with [FileHash::from_file].
+	pub fn from_file_partial(mut file: File, algorithm: HashAlgorithm) -> Result<Self, IoError> {
+		let before = FileState::from(&file.metadata()?);
+		let file_len = before.len;
+		let last_block = file_len.saturating_sub(PARTIAL_HASH_BLOCK_SIZE);
+		disable_readahead(&file);
+		let cached = [
+			CachedPages::check(&file, 0, PARTIAL_HASH_BLOCK_SIZE),
+			CachedPages::check(&file, last_block, PARTIAL_HASH_BLOCK_SIZE),
+		];
+		throttle(PARTIAL_HASH_BLOCK_SIZE * 2);
+		let mut hasher = algorithm.new_hasher();
+		let mut file_buf = [0u8; PARTIAL_HASH_BLOCK_SIZE as usize];
+		file.read_exact(&mut file_buf)?;
+		hasher.update(&file_buf);
+		file.seek(SeekFrom::Start(last_block))?;
+		file.read_exact(&mut file_buf)?;
+		hasher.update(&file_buf);
+		progress::add_bytes_done(PARTIAL_HASH_BLOCK_SIZE * 2);
+		for cached in &cached {
+			cached.drop_all_new(&file);
+		}
+		let hash = Self {
+			file_len,
+			digest: FileDigest::Partial {
+				digest: hasher.finalize(),
+			},
+		}
+		.check_stable(before, &file)?;
+		deferred_file_drop(file);
+		Ok(hash)
+	}
+}
+
+/// The bits of a file's metadata which change when it's written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+	len: u64,
+	mtime: i64,
+	mtime_nsec: i64,
+}
+impl From<&Metadata> for FileState {
+	fn from(metadata: &Metadata) -> Self {
+		Self {
+			len: metadata.len(),
+			mtime: metadata.mtime(),
+			mtime_nsec: metadata.mtime_nsec(),
+		}
+	}
+}
+
+/// Hashes the file at `path` with `hash_file`, trying again if it changed while it was being read. Files which are
+/// still changing after `--unstable-retries` attempts are left with an [FileDigest::Unstable] hash.
+fn hash_until_stable(
+	path: &IndexPath,
+	hash_file: impl Fn(File) -> Result<FileHash, IoError>,
+) -> Result<FileHash, IoError> {
+	let mut retries = 0;
+	loop {
+		let hash = File::open(path).and_then(&hash_file)?;
+		if !hash.is_unstable() {
+			return Ok(hash);
+		}
+		if retries >= CLI_ARGS.unstable_retries {
+			warning!("{path}: kept changing while it was being hashed, it won't be treated as a duplicate");
+			return Ok(hash);
+		}
+		retries += 1;
+		verbose!("{path}: changed while it was being hashed, trying again");
+	}
+}
+
+/// Every file in a group has to be verified before any of them are deleted, so a group is either resolved entirely or
+/// left alone.
+fn verify_group(kept_path: &IndexPath, paths_to_remove: &[IndexPath], file_len: u64) -> anyhow::Result<()> {
+	verify_separate_links(
+		kept_path.as_path(),
+		&paths_to_remove.iter().map(IndexPath::as_path).collect::<Vec<_>>(),
+	)?;
+	for path_to_remove in paths_to_remove {
+		verify_identical(kept_path.as_path(), path_to_remove.as_path(), file_len)?;
+	}
+	Ok(())
+}
+
+/// Identifies everything under a folder, folders with the same hash have identical trees of files in them
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, BorshDeserialize, BorshSerialize)]
+pub struct FolderHash {
+	/// Combined length of every file in the tree
+	pub total_len: u64,
+	pub file_count: u64,
+	pub digest: Box<[u8]>,
+	/// Whether every file in the tree was hashed in full, rather than only by its first and last blocks
+	pub confirmed: bool,
+}
+
+/// A (device, inode) pair
+pub type FileId = (u64, u64);
+
+/// File metadata which is used to tell whether a file has changed since it was last indexed
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct FileMeta {
+	pub mtime: i64,
+	pub mtime_nsec: i64,
+	pub ctime: i64,
+	pub ctime_nsec: i64,
+	pub device: u64,
+	pub inode: u64,
+}
+impl FileMeta {
+	/// Hardlinks to the same data all have the same ID
+	pub fn file_id(&self) -> FileId {
+		(self.device, self.inode)
+	}
+}
+impl From<&Metadata> for FileMeta {
+	fn from(metadata: &Metadata) -> Self {
+		Self {
+			mtime: metadata.mtime(),
+			mtime_nsec: metadata.mtime_nsec(),
+			ctime: metadata.ctime(),
+			ctime_nsec: metadata.ctime_nsec(),
+			device: metadata.dev(),
+			inode: metadata.ino(),
+		}
+	}
+}
+
+/// Takes the path out of the index, along with its entry in the folder it's in. Folders with a child that isn't in the
+/// index can't be hashed.
+fn remove_item(paths_to_items: &mut BTreeMap<IndexPath, FileIndexItem>, path: &IndexPath) -> Option<FileIndexItem> {
+	if let Some(FileIndexItem::Folder { contents }) = path
+		.as_path()
+		.parent()
+		.and_then(|parent| paths_to_items.get_mut(parent.as_os_str()))
+	{
+		contents.retain(|child| child != path);
+	}
+	paths_to_items.remove(path)
+}
+
+/// Removes duplicates of `kept_path` from the disk and the index, returning how many bytes were actually freed.
+/// Hardlinks to `kept_path` are left alone unless `remove_hardlinks` is set, removing them doesn't free anything.
+fn remove_group(
+	paths_to_items: &mut BTreeMap<IndexPath, FileIndexItem>,
+	paths: &mut BTreeSet<IndexPath>,
+	kept_path: &IndexPath,
+	mut paths_to_remove: Vec<IndexPath>,
+	file_len: u64,
+	remove_hardlinks: bool,
+) -> anyhow::Result<u64> {
+	let file_id = |path: &IndexPath| {
+		paths_to_items
+			.get(path)
+			.and_then(FileIndexItem::as_file_meta)
+			.map(FileMeta::file_id)
+	};
+	let kept_id = file_id(kept_path);
+	if !remove_hardlinks {
+		paths_to_remove.retain(|path| file_id(path) != kept_id);
+	}
+	// Paths under a followed symlink aren't where the file really is, deleting through them could delete the copy that
+	// was supposed to be kept, or only the link itself
+	paths_to_remove.retain(|path| {
+		let is_alias = path
+			.as_path()
+			.canonicalize()
+			.is_ok_and(|canonical| canonical != path.as_path());
+		if is_alias {
+			println!("not deleting {path}: it's reached through a symlink");
+		}
+		!is_alias
+	});
+	if paths_to_remove.is_empty() {
+		return Ok(0);
+	}
+	if let Err(err) = verify_group(kept_path, &paths_to_remove, file_len) {
+		println!("not deleting duplicates of {kept_path}: {err}");
+		return Ok(0);
+	}
+	let mut removed_ids = BTreeSet::new();
+	for path in paths_to_remove {
+		println!("deleting: {}", path);
+		fs::remove_file(&path)?;
+		paths.remove(&path);
+		if let Some(FileIndexItem::File { meta, .. }) = remove_item(paths_to_items, &path) {
+			removed_ids.insert(meta.file_id());
+		}
+	}
+	// Space is only freed once every link to the data is gone
+	for (_, meta) in paths
+		.iter()
+		.filter_map(|path| paths_to_items.get(path)?.as_file_meta().map(|meta| (path, meta)))
+	{
+		removed_ids.remove(&meta.file_id());
+	}
+	Ok(removed_ids.len() as u64 * file_len)
+}
+
+/// Empty files can be excluded from duplicate detection entirely, otherwise every one of them would be a duplicate
+/// of every other one.
+fn can_have_duplicates(file_len: u64) -> bool {
+	file_len > 0 || CLI_ARGS.empty_files == EmptyFilePolicy::Normal
+}
+
+/// How many files on the block device can be hashed at once. Spinning disks are much slower when several files are
+/// being read from them at the same time.
+fn device_jobs(device: u64) -> usize {
+	if let Some(device_jobs) = CLI_ARGS.device_jobs {
+		return device_jobs;
+	}
+	if is_rotational(device) == Some(true) {
+		let (major, minor) = dev_major_minor(device);
+		info!("device {major}:{minor} is a spinning disk, only hashing one file at a time on it");
+		return 1;
+	}
+	CLI_ARGS.jobs
+}
+
+/// Symlinks are only indexed if they're being recorded or followed. When following, any symlinks which remain are
+/// either dangling or lead to a directory which was already visited.
+fn is_indexable(file_type: FileType) -> bool {
+	file_type.is_dir() || file_type.is_file() || (file_type.is_symlink() && CLI_ARGS.symlinks != SymlinkPolicy::Ignore)
 }
 
 #[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
 pub enum FileIndexItem {
-	File { hash: FileHash },
-	Folder { contents: Vec<Arc<str>> },
+	/// The file's length is stored in `hash`
+	File {
+		hash: FileHash,
+		meta: FileMeta,
+	},
+	Folder {
+		contents: Vec<IndexPath>,
+	},
+	Symlink {
+		target: IndexPath,
+	},
 }
 
 impl FileIndexItem {
 	pub fn as_file(&self) -> Option<&FileHash> {
 		match self {
-			Self::File { hash } => Some(hash),
+			Self::File { hash, .. } => Some(hash),
 			_ => None,
 		}
 	}
-	pub fn as_folder(&self) -> Option<&[Arc<str>]> {
+	pub fn as_file_meta(&self) -> Option<&FileMeta> {
 		match self {
-			Self::Folder { contents } => Some(&contents),
+			Self::File { meta, .. } => Some(meta),
+			_ => None,
+		}
+	}
+	pub fn as_folder(&self) -> Option<&[IndexPath]> {
+		match self {
+			Self::Folder { contents } => Some(contents),
 			_ => None,
 		}
 	}
@@ -71,164 +361,651 @@ impl FileIndexItem {
 
 #[derive(Debug, Clone, Default, BorshDeserialize, BorshSerialize)]
 pub struct FileIndex {
-	pub hash_to_paths: BTreeMap<FileHash, BTreeSet<Arc<str>>>,
-	pub paths_to_items: BTreeMap<Arc<str>, FileIndexItem>,
+	pub hash_algorithm: HashAlgorithm,
+	/// The volume each of the top-level paths lives on
+	pub roots: BTreeMap<IndexPath, VolumeInfo>,
+	pub hash_to_paths: BTreeMap<FileHash, BTreeSet<IndexPath>>,
+	/// Only folders with at least one file somewhere in them are included, and only if everything in them was hashed
+	pub folder_hash_to_paths: BTreeMap<FolderHash, BTreeSet<IndexPath>>,
+	pub paths_to_items: BTreeMap<IndexPath, FileIndexItem>,
+	/// Everything that couldn't be indexed during the last scan
+	pub errors: BTreeMap<IndexPath, ScanError>,
+	/// Whether the last scan finished. If it didn't, the index is only a checkpoint and the scan should be resumed.
+	pub complete: bool,
 }
 
+/// Writes a checkpoint of the index somewhere safe
+pub type SaveIndex<'a> = dyn FnMut(&FileIndex) -> anyhow::Result<()> + 'a;
+
 impl FileIndex {
+	/// Files which were never grouped with anything, like empty files, are the only instance of themselves
 	pub fn file_instance_count(&self, hash: &FileHash) -> usize {
-		self.hash_to_paths.get(hash).map(|v| v.len()).unwrap_or_default()
+		self.hash_to_paths.get(hash).map(|v| v.len()).unwrap_or(1)
 	}
-	pub fn remove_empty_directories(&mut self, starting_with: &Path) -> anyhow::Result<()> {
-		self.paths_to_items = mem::take(&mut self.paths_to_items)
-			.into_iter()
-			.rev()
-			.filter_map(|(path, file_index_item)| -> Option<anyhow::Result<_>> {
-				let path_path = Path::new(&*path);
-				if path_path.starts_with(starting_with) && file_index_item.as_folder().is_some_and(<[_]>::is_empty) {
-					println!("deleting: {}", path);
-					match fs::remove_dir(path_path) {
-						Ok(_) => None,
-						Err(err) => Some(Err(err.into())),
-					}
-				} else {
-					Some(Ok((path, file_index_item)))
-				}
+	pub fn file_id(&self, path: &IndexPath) -> Option<FileId> {
+		self.paths_to_items
+			.get(path)
+			.and_then(FileIndexItem::as_file_meta)
+			.map(FileMeta::file_id)
+	}
+	/// Like [FileIndex::file_instance_count], but hardlinks to the same data are only counted once
+	pub fn physical_copy_count(&self, hash: &FileHash) -> usize {
+		self.hash_to_paths
+			.get(hash)
+			.map(|paths| {
+				paths
+					.iter()
+					.filter_map(|path| self.file_id(path))
+					.collect::<BTreeSet<_>>()
+					.len()
 			})
-			.collect::<anyhow::Result<BTreeMap<_, _>>>()?;
-		Ok(())
+			.unwrap_or(1)
 	}
-	pub fn remove_dupes_in_other_folders(&mut self, except: &Path) -> anyhow::Result<()> {
-		for (_, paths) in self
-			.hash_to_paths
-			.iter_mut()
-			.filter(|(_, paths)| paths.len() > 1 && paths.iter().any(|path| Path::new(&**path).starts_with(except)))
-		{
-			for path in paths.clone() {
-				let path_path = Path::new(&*path);
-				if !path_path.starts_with(except) {
-					println!("deleting: {}", path);
-					fs::remove_file(path_path)?;
-					paths.remove(&path);
-					self.paths_to_items.remove(&path);
-				}
+	pub fn remove_empty_directories(&mut self, starting_with: &Path) -> anyhow::Result<()> {
+		let folders = self
+			.paths_to_items
+			.iter()
+			.filter(|(path, item)| path.as_path().starts_with(starting_with) && item.as_folder().is_some())
+			.map(|(path, _)| path.clone())
+			.collect::<Vec<_>>();
+		// Everything inside a folder sorts after it, so going backwards gets to the sub-folders first. That way a
+		// folder which only had empty folders in it is empty by the time it's reached.
+		for path in folders.into_iter().rev() {
+			if self
+				.paths_to_items
+				.get(&path)
+				.and_then(FileIndexItem::as_folder)
+				.is_some_and(<[_]>::is_empty)
+			{
+				println!("deleting: {}", path);
+				fs::remove_dir(&path)?;
+				remove_item(&mut self.paths_to_items, &path);
 			}
 		}
+		self.update_folder_hashes();
+		Ok(())
+	}
+
+	pub fn remove_dupes_in_other_folders(&mut self, except: &Path, remove_hardlinks: bool) -> anyhow::Result<()> {
+		let mut freed_bytes = 0;
+		for (hash, paths) in self.hash_to_paths.iter_mut().filter(|(hash, paths)| {
+			hash.is_confirmed() && paths.len() > 1 && paths.iter().any(|path| path.as_path().starts_with(except))
+		}) {
+			let Some(kept_path) = paths.iter().find(|path| path.as_path().starts_with(except)).cloned() else {
+				continue;
+			};
+			let paths_to_remove = paths
+				.iter()
+				.filter(|path| !path.as_path().starts_with(except))
+				.cloned()
+				.collect::<Vec<_>>();
+			freed_bytes += remove_group(
+				&mut self.paths_to_items,
+				paths,
+				&kept_path,
+				paths_to_remove,
+				hash.file_len,
+				remove_hardlinks,
+			)?;
+		}
+		self.update_folder_hashes();
+		println!("freed {freed_bytes} bytes");
 		Ok(())
 	}
-	pub fn remove_dupes_from_folder(&mut self, folder: &Path) -> anyhow::Result<()> {
-		for (_, paths) in self.hash_to_paths.iter_mut() {
+	pub fn remove_dupes_from_folder(&mut self, folder: &Path, remove_hardlinks: bool) -> anyhow::Result<()> {
+		let mut freed_bytes = 0;
+		for (hash, paths) in self.hash_to_paths.iter_mut().filter(|(hash, _)| hash.is_confirmed()) {
 			let mut paths_to_remove = paths
 				.iter()
-				.filter(|path| Path::new(&***path).starts_with(folder) && paths.len() > 1)
+				.filter(|path| path.as_path().starts_with(folder))
 				.cloned()
 				.collect::<Vec<_>>();
 
-			if paths_to_remove.is_empty() {
+			if paths_to_remove.len() < 2 {
 				continue;
 			}
 			paths_to_remove.sort_by(|path_a, path_b| {
-				Path::new(&**path_a)
+				path_a
+					.as_path()
 					.components()
 					.count()
-					.cmp(&Path::new(&**path_b).components().count())
+					.cmp(&path_b.as_path().components().count())
 			});
-			paths_to_remove.remove(1); // Keep one with shortest path
-			while let Some(path_to_remove) = paths_to_remove.pop() {
-				println!("deleting: {}", path_to_remove);
-				fs::remove_file(&*path_to_remove)?;
-				paths.remove(&path_to_remove);
-				self.paths_to_items.remove(&path_to_remove);
-			}
+			let kept_path = paths_to_remove.remove(0); // Keep one with shortest path
+			freed_bytes += remove_group(
+				&mut self.paths_to_items,
+				paths,
+				&kept_path,
+				paths_to_remove,
+				hash.file_len,
+				remove_hardlinks,
+			)?;
 		}
+		self.update_folder_hashes();
+		println!("freed {freed_bytes} bytes");
 		Ok(())
 	}
-	pub fn from_folder(folder_path: Arc<str>) -> anyhow::Result<Self> {
-		let mut hash_to_paths: BTreeMap<FileHash, BTreeSet<Arc<str>>> = BTreeMap::new();
-		let mut paths_to_items: BTreeMap<Arc<str>, FileIndexItem> = BTreeMap::new();
-		println!("exploring: {}", folder_path);
-
-		let mut top_level_contents = Vec::new();
-		for inner_dir_entry in read_dir(&*folder_path)? {
-			let inner_dir_entry = inner_dir_entry?;
-			let inner_file_type = inner_dir_entry.file_type()?;
-			if inner_file_type.is_dir() || inner_file_type.is_file() {
-				top_level_contents.push(Arc::from(inner_dir_entry.path().to_string_lossy()));
-			}
+	/// `save` is used to checkpoint the index while hashing, so an interrupted scan can be resumed with
+	/// [FileIndex::rescan].
+	pub fn from_folders(
+		folder_paths: &[IndexPath],
+		hash_algorithm: HashAlgorithm,
+		save: &mut SaveIndex,
+	) -> anyhow::Result<Self> {
+		let mut index = Self {
+			hash_algorithm,
+			..Default::default()
+		};
+		let scan_progress = ProgressReporter::start(Stage::Scanning);
+		for folder_path in folder_paths.iter() {
+			index.extend(Self::from_folder_unhashed(folder_path.clone())?);
 		}
-		top_level_contents.sort();
-		paths_to_items.insert(
-			folder_path.clone(),
+		drop(scan_progress);
+		index.paths_to_items.insert(
+			":root".into(),
 			FileIndexItem::Folder {
-				contents: top_level_contents,
+				contents: folder_paths.to_vec(),
 			},
 		);
+		index.hash_candidates(save)?;
+		Ok(index)
+	}
+	/// The paths the index was created with
+	pub fn root_paths(&self) -> Vec<IndexPath> {
+		self.paths_to_items
+			.get(OsStr::new(":root"))
+			.and_then(FileIndexItem::as_folder)
+			.map(<[_]>::to_vec)
+			.unwrap_or_default()
+	}
+	/// Saves the index if it's been a while since the last checkpoint. If the scan was interrupted, it's saved and then
+	/// stopped.
+	fn checkpoint(&self, save: &mut SaveIndex, last_saved: &mut Instant) -> anyhow::Result<()> {
+		let interrupted = interrupt::is_interrupted();
+		if interrupted || last_saved.elapsed() >= Duration::from_secs(CLI_ARGS.checkpoint_interval) {
+			save(self)?;
+			*last_saved = Instant::now();
+		}
+		if interrupted {
+			anyhow::bail!(
+				"Interrupted, the progress so far has been saved. Run again with the same index to continue."
+			);
+		}
+		Ok(())
+	}
+	fn record_error(&mut self, path: IndexPath, err: &IoError) {
+		warning!("{path}: {err}");
+		self.errors.insert(path, ScanError::from(err));
+	}
+	/// Prints how many paths couldn't be indexed, if there were any
+	pub fn print_error_summary(&self) {
+		if self.errors.is_empty() {
+			return;
+		}
+		let mut kind_counts: BTreeMap<ScanErrorKind, usize> = BTreeMap::new();
+		for error in self.errors.values() {
+			*kind_counts.entry(error.kind).or_default() += 1;
+		}
+		let kind_counts = kind_counts
+			.into_iter()
+			.map(|(kind, count)| format!("{count} {kind}"))
+			.collect::<Vec<_>>()
+			.join(", ");
+		warning!(
+			"{} paths couldn't be indexed ({kind_counts}), enter \"errors\" to list them",
+			self.errors.len()
+		);
+	}
+	/// Walks the folder and stats every file, but doesn't hash anything. All files will only have a [FileDigest::Size]
+	/// digest. Anything which can't be read is recorded in [FileIndex::errors] and skipped.
+	fn from_folder_unhashed(folder_path: IndexPath) -> anyhow::Result<Self> {
+		let mut index = Self::default();
+		verbose!("exploring: {}", folder_path);
 
-		for iter_result in multi_thread_map_iter(
-			DeepReadDir::new(Path::new(folder_path.as_ref()))?.filter_map(
-				|dir_entry| -> Option<anyhow::Result<(DirEntry, FileType)>> {
-					match dir_entry {
-						Ok(dir_entry) => match dir_entry.file_type() {
-							Ok(file_type) if file_type.is_dir() || file_type.is_file() => {
-								Some(Ok((dir_entry, file_type)))
-							},
-							Ok(_) => {
-								eprintln!("{}: ignoring special/system file", dir_entry.path().to_string_lossy());
-								None
-							},
-							Err(err) => Some(Err(err.into())),
-						},
-						Err(err) => Some(Err(err.into())),
-					}
-				},
-			),
-			|dir_entry| -> anyhow::Result<(Arc<str>, FileIndexItem)> {
-				let (dir_entry, file_type) = dir_entry?;
-				let file_path: Arc<str> = Arc::from(dir_entry.path().to_string_lossy());
-				// let file_path_str = file_path.to_string_lossy();
-				if file_type.is_dir() {
-					println!("indexing: {file_path}");
-					let mut contents = Vec::new();
-					// Yeah, some folders are going to get read twice, whatever.
-					for inner_dir_entry in read_dir(&*file_path)? {
-						let inner_dir_entry = inner_dir_entry?;
-						let inner_file_type = inner_dir_entry.file_type()?;
-						if inner_file_type.is_dir() || inner_file_type.is_file() {
-							contents.push(Arc::from(inner_dir_entry.path().to_string_lossy()));
-						}
-					}
-					contents.sort();
-					return Ok((file_path, FileIndexItem::Folder { contents }));
+		let scan_filter = ScanFilter {
+			include: CLI_ARGS.include.clone(),
+			exclude: CLI_ARGS.exclude.clone(),
+			use_ignore_files: CLI_ARGS.ignore_files,
+			file_size: SizeRange {
+				min: CLI_ARGS.min_size,
+				max: CLI_ARGS.max_size,
+			},
+			empty_files: CLI_ARGS.empty_files,
+		};
+		let walker = match DirWalker::new(
+			folder_path.as_path(),
+			CLI_ARGS.symlinks,
+			CLI_ARGS.one_file_system,
+			scan_filter.clone(),
+		)
+		.and_then(|walker| Ok((walker, VolumeInfo::from_path(folder_path.as_path())?)))
+		{
+			Ok((walker, volume)) => {
+				index.roots.insert(folder_path, volume);
+				walker
+			},
+			Err(err) => {
+				index.record_error(folder_path, &err);
+				return Ok(index);
+			},
+		};
+		walker.walk(
+			CLI_ARGS.jobs,
+			|path, file_type| -> Result<Option<FileIndexItem>, IoError> {
+				if !is_indexable(file_type) {
+					verbose!("{}: ignoring special/system file", escaped(path));
+					Ok(None)
 				} else if file_type.is_file() {
-					println!("hashing: {file_path}");
-					let hash = FileHash::from_file(File::open(&*file_path)?)?;
-					println!("hashed: {file_path}");
-					return Ok((file_path, FileIndexItem::File { hash }));
+					// The entry itself might be a symlink that's being followed
+					let metadata = fs::metadata(path)?;
+					if !scan_filter.is_file_len_allowed(metadata.len()) {
+						return Ok(None);
+					}
+					Ok(Some(FileIndexItem::File {
+						hash: FileHash::from_len(metadata.len()),
+						meta: FileMeta::from(&metadata),
+					}))
 				} else {
-					unreachable!("dir entry should have already been filtered")
+					Ok(Some(FileIndexItem::Symlink {
+						target: fs::read_link(path)?.into(),
+					}))
+				}
+			},
+			|dir_contents| {
+				if interrupt::is_interrupted() {
+					// Nothing has been hashed yet, so there's nothing worth saving
+					anyhow::bail!("Interrupted while scanning, nothing new has been saved.");
+				}
+				let dir_path = IndexPath::from(dir_contents.path);
+				verbose!("indexing: {dir_path}");
+				progress::add_found(1, dir_contents.items.len() as u64);
+				for (path, err) in dir_contents.errors {
+					index.record_error(path.into(), &err);
 				}
+				index.paths_to_items.extend(
+					dir_contents
+						.items
+						.into_iter()
+						.map(|(path, item)| (IndexPath::from(path), item)),
+				);
+				let mut contents = dir_contents
+					.children
+					.into_iter()
+					.map(IndexPath::from)
+					.collect::<Vec<_>>();
+				contents.sort();
+				index
+					.paths_to_items
+					.insert(dir_path, FileIndexItem::Folder { contents });
+				Ok(())
 			},
+		)?;
+		Ok(index)
+	}
+	/// Files grouped by their length. Hardlinks are the same file as far as this is concerned, so only one of the
+	/// paths to each one is included.
+	fn size_buckets(&self) -> BTreeMap<u64, Vec<(IndexPath, FileDigest)>> {
+		let mut size_buckets: BTreeMap<u64, BTreeMap<FileId, (IndexPath, FileDigest)>> = BTreeMap::new();
+		for (path, item) in self.paths_to_items.iter() {
+			if let FileIndexItem::File { hash, meta } = item {
+				size_buckets
+					.entry(hash.file_len)
+					.or_default()
+					.entry(meta.file_id())
+					.or_insert_with(|| (path.clone(), hash.digest.clone()));
+			}
+		}
+		size_buckets
+			.into_iter()
+			.map(|(file_len, files)| (file_len, files.into_values().collect()))
+			.collect()
+	}
+	fn paths_by_file_id(&self) -> BTreeMap<FileId, Vec<IndexPath>> {
+		let mut paths_by_file_id: BTreeMap<FileId, Vec<IndexPath>> = BTreeMap::new();
+		for (path, item) in self.paths_to_items.iter() {
+			if let Some(meta) = item.as_file_meta() {
+				paths_by_file_id.entry(meta.file_id()).or_default().push(path.clone());
+			}
+		}
+		paths_by_file_id
+	}
+	/// Sets the hash of the file at `path`, and any other hardlinks to it
+	fn set_file_hash(
+		&mut self,
+		path: &IndexPath,
+		new_hash: FileHash,
+		paths_by_file_id: &BTreeMap<FileId, Vec<IndexPath>>,
+	) {
+		let Some(links) = self.file_id(path).and_then(|file_id| paths_by_file_id.get(&file_id)) else {
+			return;
+		};
+		for link in links {
+			if let Some(FileIndexItem::File { hash, .. }) = self.paths_to_items.get_mut(link) {
+				*hash = new_hash.clone();
+			}
+		}
+	}
+	/// Drops a file which couldn't be hashed from the index, along with any other hardlinks to it
+	fn remove_unreadable_file(
+		&mut self,
+		path: &IndexPath,
+		err: &IoError,
+		paths_by_file_id: &BTreeMap<FileId, Vec<IndexPath>>,
+	) {
+		let links = self
+			.file_id(path)
+			.and_then(|file_id| paths_by_file_id.get(&file_id))
+			.cloned()
+			.unwrap_or_else(|| vec![path.clone()]);
+		for link in links {
+			remove_item(&mut self.paths_to_items, &link);
+			self.record_error(link, err);
+		}
+	}
+	/// Splits the files up by which block device they're on, along with how many of them can be hashed at once. Devices
+	/// are hashed in parallel with each other, and files on the same one are read in inode order, which is roughly the
+	/// order they're laid out on disk. Filesystems which share a disk, like btrfs subvolumes, share its limit too.
+	fn group_by_device(&self, paths: Vec<IndexPath>) -> Vec<(std::vec::IntoIter<IndexPath>, usize)> {
+		let mut backing_devices = BTreeMap::new();
+		let mut paths_by_device: BTreeMap<u64, Vec<(FileId, IndexPath)>> = BTreeMap::new();
+		for path in paths {
+			let file_id = self.file_id(&path).unwrap_or_default();
+			let device = *backing_devices
+				.entry(file_id.0)
+				.or_insert_with(|| backing_device(file_id.0).unwrap_or(file_id.0));
+			paths_by_device.entry(device).or_default().push((file_id, path));
+		}
+		paths_by_device
+			.into_iter()
+			.map(|(device, mut paths)| {
+				paths.sort();
+				let paths = paths.into_iter().map(|(_, path)| path).collect::<Vec<_>>();
+				(paths.into_iter(), device_jobs(device))
+			})
+			.collect()
+	}
+	/// Files are compared in stages, only the files which match at one stage are read more thoroughly at the next.
+	/// Files with a unique length can't have any duplicates, so there's no point in reading them at all. Then the first
+	/// and last blocks of each file are hashed, and only files which still collide get their entire contents hashed.
+	///
+	/// Files which already have a digest from a previous scan aren't read again unless a new file might match them.
+	fn hash_candidates(&mut self, save: &mut SaveIndex) -> anyhow::Result<()> {
+		let paths_by_file_id = self.paths_by_file_id();
+		let mut last_saved = Instant::now();
+		let mut partial_candidates = Vec::new();
+		let mut full_candidates = Vec::new();
+		for (file_len, files) in self.size_buckets() {
+			if files.len() < 2 || !can_have_duplicates(file_len) {
+				continue;
+			}
+			for (path, digest) in files {
+				if digest != FileDigest::Size {
+					continue;
+				}
+				if file_len <= PARTIAL_HASH_BLOCK_SIZE * 2 {
+					// The partial hash would read the entire file anyway
+					full_candidates.push(path);
+				} else {
+					partial_candidates.push(path);
+				}
+			}
+		}
+		info!("{} files need to be partially hashed", partial_candidates.len());
+		let hash_progress = ProgressReporter::start(Stage::PartialHashing);
+		progress::add_total(
+			partial_candidates.len() as u64,
+			partial_candidates.len() as u64 * PARTIAL_HASH_BLOCK_SIZE * 2,
+		);
+
+		let hash_algorithm = self.hash_algorithm;
+		parallel_map(
+			CLI_ARGS.jobs,
+			self.group_by_device(partial_candidates),
+			|file_path| -> (IndexPath, Result<FileHash, IoError>) {
+				verbose!("partially hashing: {file_path}");
+				let hash = hash_until_stable(&file_path, |file| FileHash::from_file_partial(file, hash_algorithm));
+				(file_path, hash)
+			},
+			|(path, hash)| {
+				match hash {
+					Ok(new_hash) => self.set_file_hash(&path, new_hash, &paths_by_file_id),
+					// It's hashed again when the scan is resumed
+					Err(err) if err.kind() == IoErrorKind::Interrupted => {},
+					Err(err) => self.remove_unreadable_file(&path, &err, &paths_by_file_id),
+				}
+				progress::add_file_done();
+				self.checkpoint(save, &mut last_saved)
+			},
+		)?;
+		drop(hash_progress);
+		if !CLI_ARGS.quick {
+			for files in self.size_buckets().into_values() {
+				// A partial digest can't be compared to a full one, so if a file in this bucket has already been
+				// fully hashed, everything else in it has to be too.
+				let has_full = files
+					.iter()
+					.any(|(_, digest)| matches!(digest, FileDigest::Full { .. }));
+				let mut partial_counts: BTreeMap<&FileDigest, usize> = BTreeMap::new();
+				for (_, digest) in files.iter() {
+					*partial_counts.entry(digest).or_default() += 1;
+				}
+				full_candidates.extend(
+					files
+						.iter()
+						.filter(|(_, digest)| {
+							matches!(digest, FileDigest::Partial { .. }) && (has_full || partial_counts[digest] > 1)
+						})
+						.map(|(path, _)| path.clone()),
+				);
+			}
+		}
+		info!("{} files need to be hashed", full_candidates.len());
+		let hash_progress = ProgressReporter::start(Stage::Hashing);
+		progress::add_total(
+			full_candidates.len() as u64,
+			full_candidates
+				.iter()
+				.filter_map(|path| Some(self.paths_to_items.get(path)?.as_file()?.file_len))
+				.sum(),
+		);
+
+		parallel_map(
 			CLI_ARGS.jobs,
-		) {
-			let (path, index_item) = iter_result?;
-			match &index_item {
-				FileIndexItem::File { hash } => {
-					hash_to_paths.entry(*hash).or_default().insert(path.clone());
+			self.group_by_device(full_candidates),
+			|file_path| -> (IndexPath, Result<FileHash, IoError>) {
+				verbose!("hashing: {file_path}");
+				let hash = hash_until_stable(&file_path, |file| FileHash::from_file(file, hash_algorithm));
+				if hash.is_ok() {
+					verbose!("hashed: {file_path}");
+				}
+				(file_path, hash)
+			},
+			|(path, hash)| {
+				match hash {
+					Ok(new_hash) => self.set_file_hash(&path, new_hash, &paths_by_file_id),
+					// It's hashed again when the scan is resumed
+					Err(err) if err.kind() == IoErrorKind::Interrupted => {},
+					Err(err) => self.remove_unreadable_file(&path, &err, &paths_by_file_id),
+				}
+				progress::add_file_done();
+				self.checkpoint(save, &mut last_saved)
+			},
+		)?;
+
+		drop(hash_progress);
+
+		self.complete = true;
+		self.hash_to_paths.clear();
+		for (path, item) in self.paths_to_items.iter() {
+			if let Some(hash) = item
+				.as_file()
+				.filter(|hash| can_have_duplicates(hash.file_len) && !hash.is_unstable())
+			{
+				self.hash_to_paths.entry(hash.clone()).or_default().insert(path.clone());
+			}
+		}
+		self.update_folder_hashes();
+		Ok(())
+	}
+	/// Hashes the names and hashes of everything in the folder. Sub-folders have to have been hashed already. Returns
+	/// `None` if anything in it doesn't have a usable hash, since then it can't be told apart from anything else.
+	fn folder_hash(
+		&self,
+		contents: &[IndexPath],
+		folder_hashes: &BTreeMap<&IndexPath, FolderHash>,
+	) -> Option<FolderHash> {
+		let mut hasher = self.hash_algorithm.new_hasher();
+		let mut folder_hash = FolderHash {
+			total_len: 0,
+			file_count: 0,
+			digest: Box::default(),
+			confirmed: true,
+		};
+		// Everything is length prefixed so different trees can't end up hashing the same bytes
+		let mut update = |tag: &[u8], data: &[u8]| {
+			hasher.update(tag);
+			hasher.update(&(data.len() as u64).to_le_bytes());
+			hasher.update(data);
+		};
+		// Contents are sorted, so the same names always come in the same order
+		for child in contents {
+			update(b"N", child.as_path().file_name()?.as_bytes());
+			match self.paths_to_items.get(child)? {
+				FileIndexItem::File { hash, .. } => {
+					if hash.is_unstable() {
+						return None;
+					}
+					// Files that only have a size digest never collided with anything, so they still tell folders apart
+					folder_hash.confirmed &= !matches!(hash.digest, FileDigest::Partial { .. });
+					update(b"F", &borsh::to_vec(hash).ok()?);
+					folder_hash.total_len += hash.file_len;
+					folder_hash.file_count += 1;
 				},
-				_ => {},
+				FileIndexItem::Folder { .. } => {
+					let child_hash = folder_hashes.get(child)?;
+					update(b"D", &child_hash.digest);
+					folder_hash.total_len += child_hash.total_len;
+					folder_hash.file_count += child_hash.file_count;
+					folder_hash.confirmed &= child_hash.confirmed;
+				},
+				FileIndexItem::Symlink { target } => update(b"L", target.as_bytes()),
 			}
-			paths_to_items.insert(path, index_item);
 		}
-		Ok(Self {
-			hash_to_paths,
-			paths_to_items,
-		})
+		folder_hash.digest = hasher.finalize();
+		Some(folder_hash)
+	}
+	/// Rebuilds [FileIndex::folder_hash_to_paths] from the hashes of the files in each folder
+	fn update_folder_hashes(&mut self) {
+		// A folder with something in it that couldn't be indexed, or which couldn't be listed itself, isn't known in full.
+		// Leaving it out means everything it's in is left out too.
+		let incomplete_folders = self
+			.errors
+			.keys()
+			.flat_map(|path| [Some(path.as_path()), path.as_path().parent()])
+			.flatten()
+			.collect::<BTreeSet<_>>();
+		let mut folder_hashes = BTreeMap::new();
+		// Everything inside a folder sorts after it, so going backwards gets to the sub-folders first
+		for (path, item) in self.paths_to_items.iter().rev() {
+			let FileIndexItem::Folder { contents } = item else {
+				continue;
+			};
+			if path.as_bytes() == b":root" || incomplete_folders.contains(path.as_path()) {
+				continue;
+			}
+			if let Some(folder_hash) = self.folder_hash(contents, &folder_hashes) {
+				folder_hashes.insert(path, folder_hash);
+			}
+		}
+		let mut folder_hash_to_paths: BTreeMap<FolderHash, BTreeSet<IndexPath>> = BTreeMap::new();
+		for (path, folder_hash) in folder_hashes {
+			if folder_hash.file_count > 0 {
+				folder_hash_to_paths
+					.entry(folder_hash)
+					.or_default()
+					.insert(path.clone());
+			}
+		}
+		self.folder_hash_to_paths = folder_hash_to_paths;
+	}
+	/// Groups of identical folders, outermost first. Folders inside a duplicate folder are left out when whatever
+	/// they're in is already listed, since dealing with the outer folder deals with them too.
+	pub fn duplicate_folders(&self) -> Vec<(&FolderHash, &BTreeSet<IndexPath>)> {
+		let duplicated = self
+			.folder_hash_to_paths
+			.values()
+			.filter(|paths| paths.len() > 1)
+			.flatten()
+			.collect::<BTreeSet<_>>();
+		let depth = |path: &IndexPath| path.as_path().components().count();
+		let mut groups = self
+			.folder_hash_to_paths
+			.iter()
+			.filter(|(_, paths)| paths.len() > 1)
+			.filter(|(_, paths)| {
+				!paths.iter().all(|path| {
+					path.as_path()
+						.parent()
+						.is_some_and(|parent| duplicated.contains(&IndexPath::from(parent)))
+				})
+			})
+			.collect::<Vec<_>>();
+		groups.sort_by_key(|(folder_hash, paths)| {
+			(paths.iter().map(depth).min(), std::cmp::Reverse(folder_hash.total_len))
+		});
+		groups
+	}
+	/// Other folders with exactly the same contents as this one
+	pub fn folder_duplicates(&self, path: &OsStr) -> Option<(&FolderHash, &BTreeSet<IndexPath>)> {
+		self.folder_hash_to_paths.iter().find(|(_, paths)| paths.contains(path))
+	}
+	/// Walks the roots again, only files which were added or whose metadata changed since the last scan get hashed.
+	/// Anything which no longer exists is dropped from the index. This is also how an interrupted scan is resumed, since
+	/// any hashes that were checkpointed are kept.
+	pub fn rescan(&mut self, save: &mut SaveIndex) -> anyhow::Result<()> {
+		let roots = self.root_paths();
+		let mut new_index = Self {
+			hash_algorithm: self.hash_algorithm,
+			..Default::default()
+		};
+		let scan_progress = ProgressReporter::start(Stage::Scanning);
+		for root in roots.iter() {
+			new_index.extend(Self::from_folder_unhashed(root.clone())?);
+		}
+		drop(scan_progress);
+		let mut unchanged_count = 0;
+		for (path, new_item) in new_index.paths_to_items.iter_mut() {
+			let (
+				FileIndexItem::File { hash, meta },
+				Some(FileIndexItem::File {
+					hash: old_hash,
+					meta: old_meta,
+				}),
+			) = (new_item, self.paths_to_items.get(path))
+			else {
+				continue;
+			};
+			// Unstable files get another go, they might have settled down since
+			if meta == old_meta && hash.file_len == old_hash.file_len && !old_hash.is_unstable() {
+				*hash = old_hash.clone();
+				unchanged_count += 1;
+			}
+		}
+		info!("{unchanged_count} files haven't changed since the last scan");
+		new_index
+			.paths_to_items
+			.insert(":root".into(), FileIndexItem::Folder { contents: roots });
+		new_index.hash_candidates(save)?;
+		*self = new_index;
+		Ok(())
 	}
 	pub fn extend(&mut self, other: FileIndex) {
 		for (other_hash, other_paths) in other.hash_to_paths {
 			let self_paths = self.hash_to_paths.entry(other_hash).or_default();
 			self_paths.extend(other_paths);
 		}
-		self.paths_to_items.extend(other.paths_to_items.into_iter());
+		self.paths_to_items.extend(other.paths_to_items);
+		self.roots.extend(other.roots);
+		self.errors.extend(other.errors);
 	}
 }
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set while a scan is running, Ctrl-C only asks the scan to stop instead of killing the process straight away
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Handles SIGINT and SIGTERM. During a scan the first one lets it save what it has done so far, a second one exits
+/// immediately.
+pub fn install_handler() -> anyhow::Result<()> {
+	ctrlc::set_handler(|| {
+		if !SCANNING.load(Ordering::Relaxed) || INTERRUPTED.swap(true, Ordering::Relaxed) {
+			std::process::exit(130);
+		}
+		eprintln!("\nStopping, press Ctrl-C again to quit without saving...");
+	})?;
+	Ok(())
+}
+
+pub fn is_interrupted() -> bool {
+	INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Marks a scan as running until it's dropped
+#[derive(Debug)]
+pub struct ScanGuard;
+impl ScanGuard {
+	pub fn start() -> Self {
+		SCANNING.store(true, Ordering::Relaxed);
+		Self
+	}
+}
+impl Drop for ScanGuard {
+	fn drop(&mut self) {
+		SCANNING.store(false, Ordering::Relaxed);
+	}
+}
@@ -1,19 +1,38 @@
 use std::{
-	fs::OpenOptions,
-	io::{Seek, Write},
+	fs::{self, File},
+	io::{ErrorKind as IoErrorKind, Write},
 	path::{Path, PathBuf},
-	sync::{Arc, LazyLock},
+	sync::LazyLock,
 };
 
 use bpaf::Bpaf;
 use const_format::concatcp;
+use dir_walker::SymlinkPolicy;
 use file_closer::stop_file_closer_thread;
-use indexer::{FileIndex, FileIndexItem};
-mod deep_readdir;
+use hasher::HashAlgorithm;
+use index_file::{Compression, SaveOptions};
+use index_path::{escaped, IndexPath};
+use indexer::{FileHash, FileIndex, FileIndexItem};
+use interrupt::ScanGuard;
+use progress::{format_bytes, info};
+use scan_filter::{ByteSize, EmptyFilePolicy, PathPattern, SizeRange};
+mod dir_walker;
 mod file_closer;
+mod file_reader;
+mod hasher;
+mod index_file;
+mod index_path;
 mod indexer;
-mod multi_thread_iter;
-const VERSION_INFO: &'static str = concatcp!(
+mod interrupt;
+mod mounts;
+mod overlap;
+mod progress;
+mod scan_error;
+mod scan_filter;
+mod throttle;
+mod verify;
+mod worker_pool;
+const VERSION_INFO: &str = concatcp!(
 	env!("CARGO_PKG_NAME"),
 	" ",
 	env!("BUILD_VERSION"),
@@ -32,7 +51,7 @@ const VERSION_INFO: &'static str = concatcp!(
 );
 
 // Would have loved to use Cow, but bpaf doesn't like that
-pub fn space_seperation<'a>(mut input: &'a str) -> Vec<String> {
+pub fn space_seperation(mut input: &str) -> Vec<String> {
 	input = input.trim();
 	if input == "help" {
 		return vec!["--help".into()];
@@ -100,6 +119,10 @@ pub fn space_seperation<'a>(mut input: &'a str) -> Vec<String> {
 				if escape_str.is_empty() {
 					escape_str.push_str(&input[start_index..(i - 1)]);
 				}
+				// These are left for paths to unescape, see IndexPath's FromStr
+				if c == '\\' || c == 'x' {
+					escape_str.push('\\');
+				}
 				escape_str.push(c);
 				if escaping_in_quote {
 					state = ThingState::Quote;
@@ -136,10 +159,89 @@ pub struct InvokeArgs {
 	/// Number of threads to use during indexing. Defaults to the number of CPU threads the system reports.
 	#[bpaf(argument("COUNT"), short, long, fallback(num_cpus::get()))]
 	jobs: usize,
+	/// Number of files to hash at once on each disk. Defaults to 1 on spinning disks, so they don't spend all their time
+	/// seeking, and --jobs on anything else. No more than --jobs files are hashed at once in total.
+	#[bpaf(argument("COUNT"), long)]
+	device_jobs: Option<usize>,
+	/// Only hash the first and last blocks of files. Duplicates will only be reported as probable, and won't be deleted.
+	#[bpaf(short, long)]
+	quick: bool,
+	/// Hash algorithm to use when creating an index: blake3, xxh3, sha256 or sha256+sha512. Defaults to sha256+sha512.
+	/// Must match the algorithm an existing index was created with.
+	#[bpaf(argument("ALGORITHM"), long("hash"))]
+	hash_algorithm: Option<HashAlgorithm>,
 	/// Save the hash index
 	#[bpaf(argument("FILE"), short, long)]
 	index: PathBuf,
-	/// Paths to traverse
+	/// Compression for the index file: none or zstd. Defaults to whatever an existing index uses, or none for a new
+	/// one. An existing index is converted straight away if this is different.
+	#[bpaf(argument("COMPRESSION"), long)]
+	compression: Option<Compression>,
+	/// What to do with symlinks: ignore, record or follow. Defaults to ignore.
+	#[bpaf(argument("POLICY"), long("symlinks"), fallback(SymlinkPolicy::Ignore))]
+	symlinks: SymlinkPolicy,
+	/// Don't descend into directories on other filesystems
+	#[bpaf(short('x'), long)]
+	one_file_system: bool,
+	/// Skip anything matching this pattern, excluded directories aren't descended into. Patterns are globs, which
+	/// are matched against the file name unless they contain a "/", or regexes matched against the full path if
+	/// prefixed with "re:". Globs with a "/" match the end of the full path, unless they start with one. Can be given
+	/// multiple times.
+	#[bpaf(argument("PATTERN"), long)]
+	exclude: Vec<PathPattern>,
+	/// Only index files matching at least one of these patterns, same syntax as --exclude. Can be given multiple
+	/// times.
+	#[bpaf(argument("PATTERN"), long)]
+	include: Vec<PathPattern>,
+	/// Read a .fdupesignore file in each directory, written with gitignore syntax
+	#[bpaf(long)]
+	ignore_files: bool,
+	/// Only index files at least this big, e.g. 4K, 1.5MiB or 10MB
+	#[bpaf(argument("SIZE"), long)]
+	min_size: Option<ByteSize>,
+	/// Only index files at most this big
+	#[bpaf(argument("SIZE"), long)]
+	max_size: Option<ByteSize>,
+	/// What to do with empty files: exclude them, index them as unique files, or treat them normally. Defaults to
+	/// unique.
+	#[bpaf(argument("POLICY"), long, fallback(EmptyFilePolicy::Unique))]
+	empty_files: EmptyFilePolicy,
+	/// Only print warnings and errors while indexing
+	#[bpaf(long)]
+	quiet: bool,
+	/// Print every file and directory as it's indexed and hashed
+	#[bpaf(short, long)]
+	verbose: bool,
+	/// Walk the paths in an existing index again, only re-hashing files which were added or changed since it was made
+	#[bpaf(long)]
+	rescan: bool,
+	/// How often to save progress to the index while hashing, in seconds. Progress is also saved when interrupted with
+	/// Ctrl-C, running again with the same index carries on from there.
+	#[bpaf(argument("SECONDS"), long, fallback(300))]
+	checkpoint_interval: u64,
+	/// How many times to hash a file again if it changes while it's being read. Files that still keep changing are
+	/// marked as unstable, and are never treated as duplicates.
+	#[bpaf(argument("COUNT"), long, fallback(3))]
+	unstable_retries: u32,
+	/// Don't read more than this much per second while hashing, e.g. 50MiB
+	#[bpaf(argument("SIZE"), long)]
+	max_read_rate: Option<ByteSize>,
+	/// Only use the disk when nothing else wants it and run at the lowest CPU priority, for scanning in the background
+	/// on a busy machine
+	#[bpaf(long)]
+	low_priority: bool,
+	/// How much of a file to read at once while hashing
+	#[bpaf(argument("SIZE"), long, fallback(ByteSize(512 * 1024)))]
+	read_buffer_size: ByteSize,
+	/// Map files at least this big into memory instead of reading them. Files that get truncated while they're being
+	/// hashed this way crash fdupes with SIGBUS.
+	#[bpaf(argument("SIZE"), long)]
+	mmap_min_size: Option<ByteSize>,
+	/// Leave hashed files in the page cache. By default whatever a scan reads is dropped from it again, so the scan
+	/// doesn't push out everything else that's cached. Parts of files which were already cached are left alone.
+	#[bpaf(long)]
+	keep_cache: bool,
+	/// Paths to traverse, only used when creating a new index
 	#[bpaf(positional("PATH"))]
 	path: Vec<PathBuf>,
 }
@@ -164,34 +266,66 @@ pub enum Commands {
 		recursive: bool,
 		#[bpaf(short, long)]
 		duplicates: bool,
+		/// Only list files at least this big
+		#[bpaf(argument("SIZE"), long)]
+		min_size: Option<ByteSize>,
+		/// Only list files at most this big
+		#[bpaf(argument("SIZE"), long)]
+		max_size: Option<ByteSize>,
 	},
 	#[bpaf(command)]
 	Info {
 		#[bpaf(positional("FILE"))]
-		file: PathBuf,
+		file: IndexPath,
 	},
 	#[bpaf(command)]
 	Cd {
 		#[bpaf(positional("DIR"))]
-		dir: PathBuf,
+		dir: IndexPath,
 	},
 	#[bpaf(command)]
 	/// Removes all empty directories within....
 	Rmedir {
 		#[bpaf(positional("DIR"))]
-		dir: PathBuf,
+		dir: IndexPath,
 	},
 	#[bpaf(command)]
 	/// Removes files from all other folders which are duplicates of any files within this folder
 	Rmodupes {
+		/// Also remove hardlinks to the files being kept, this doesn't free any space
+		#[bpaf(short('l'), long)]
+		hardlinks: bool,
 		#[bpaf(positional("DIR"))]
-		dir: PathBuf,
+		dir: IndexPath,
 	},
 	#[bpaf(command)]
 	/// Removes all duplicates within the specified folder, keeping the one with the shortest path
 	Rmdupes {
+		/// Also remove hardlinks to the files being kept, this doesn't free any space
+		#[bpaf(short('l'), long)]
+		hardlinks: bool,
 		#[bpaf(positional("DIR"))]
-		dir: PathBuf,
+		dir: IndexPath,
+	},
+	#[bpaf(command)]
+	/// Lists everything that couldn't be indexed during the last scan
+	Errors,
+	#[bpaf(command)]
+	/// Lists folders whose entire contents are duplicated somewhere else, outermost first
+	Dupdirs {
+		/// Only list folders holding at least this much
+		#[bpaf(argument("SIZE"), long)]
+		min_size: Option<ByteSize>,
+	},
+	#[bpaf(command)]
+	/// Lists pairs of folders which have a lot of the same files in them, most overlapping first
+	Overlap {
+		/// Only list pairs where at least this much of one folder is in the other, in percent
+		#[bpaf(argument("PERCENT"), long, fallback(50.0))]
+		min: f64,
+		/// How many pairs to list at most
+		#[bpaf(argument("COUNT"), long, fallback(20))]
+		limit: usize,
 	},
 	#[bpaf(command)]
 	/// Prints version info
@@ -201,36 +335,102 @@ pub enum Commands {
 	Quit,
 }
 
+/// Duplicate counts which were only matched by a partial hash are marked with a "?". Extra hardlinks aren't counted as
+/// copies, they're listed separately.
+fn dupe_count_label(hash: &FileHash, copy_count: usize, link_count: usize) -> String {
+	let mut label = copy_count.to_string();
+	if copy_count > 1 && !hash.is_confirmed() {
+		label.push('?');
+	}
+	if link_count > 0 {
+		label.push_str(&format!(" +{link_count} hardlinks"));
+	}
+	label
+}
+
+fn write_index(index: &FileIndex, options: SaveOptions) -> anyhow::Result<()> {
+	index_file::save_index(&CLI_ARGS.index, index, options)
+}
+
+#[cfg(not(test))]
 static CLI_ARGS: LazyLock<InvokeArgs> = LazyLock::new(|| invoke_args().run());
-// There's some quick and dirty stuff, this all used to be based around Path and PathBuf,
-// But those types don't have borsh serialization, so now it's all str and String... should be fine
+// The test runner's own arguments aren't ours, and tests shouldn't depend on how they were run anyway
+#[cfg(test)]
+static CLI_ARGS: LazyLock<InvokeArgs> =
+	LazyLock::new(|| invoke_args().run_inner(&["--index", "test.idx", "--quiet"]).unwrap());
+// Paths in the index are IndexPaths, since Path and PathBuf don't have borsh serialization. The REPL still works with
+// PathBufs, which can be used to look things up in the index as-is.
 fn main() -> anyhow::Result<()> {
-	if CLI_ARGS.path.is_empty() {
-		anyhow::bail!("Needs at least one path")
+	// Has to happen before any other threads are started, they only inherit the priority when they're spawned
+	if CLI_ARGS.low_priority {
+		throttle::lower_priority();
 	}
-	println!("Creating index with {} threads...", CLI_ARGS.jobs);
-	let mut index = FileIndex::default();
-	let mut index_file = (&*OpenOptions::new().read(true).write(true).create(true)).open(&CLI_ARGS.index)?;
-	if index_file.metadata()?.len() == 0 {
-		let mut virtual_root_contents: Vec<Arc<str>> = Vec::new();
+	interrupt::install_handler()?;
+	let mut index;
+	// Kept as-is whenever the index is written again
+	let save_options;
+	index_file::check_writable(&CLI_ARGS.index)?;
+	let index_len = match fs::metadata(&CLI_ARGS.index) {
+		Ok(metadata) => metadata.len(),
+		Err(err) if err.kind() == IoErrorKind::NotFound => 0,
+		Err(err) => return Err(err.into()),
+	};
+	if index_len == 0 {
+		if CLI_ARGS.path.is_empty() {
+			anyhow::bail!("Needs at least one path")
+		}
+		let mut virtual_root_contents: Vec<IndexPath> = Vec::new();
 		for path in CLI_ARGS.path.iter() {
-			let path = path.canonicalize()?;
-			index.extend(FileIndex::from_folder(path.to_string_lossy().into())?);
-			virtual_root_contents.push(path.to_string_lossy().into());
+			virtual_root_contents.push(path.canonicalize()?.into());
 		}
-		index.paths_to_items.insert(
-			":root".into(),
-			FileIndexItem::Folder {
-				contents: virtual_root_contents,
-			},
+		let hash_algorithm = CLI_ARGS.hash_algorithm.unwrap_or_default();
+		info!(
+			"Creating index with {} threads using {hash_algorithm}...",
+			CLI_ARGS.jobs
 		);
-		println!("Writing index file...");
-		index_file.rewind()?;
-		borsh::to_writer(&mut index_file, &index)?;
-		println!("Saving file...");
-		index_file.flush()?;
+		let _scan_guard = ScanGuard::start();
+		save_options = SaveOptions {
+			created_at: index_file::unix_time_now(),
+			compression: CLI_ARGS.compression.unwrap_or_default(),
+		};
+		// All roots are scanned together so files of the same size in different roots still get compared
+		index = FileIndex::from_folders(&virtual_root_contents, hash_algorithm, &mut |index| {
+			write_index(index, save_options)
+		})?;
+		index.print_error_summary();
+		write_index(&index, save_options)?;
 	} else {
-		index = borsh::de::from_reader(&mut index_file)?;
+		let header;
+		(header, index) = index_file::read_index(&mut File::open(&CLI_ARGS.index)?)?;
+		save_options = SaveOptions {
+			compression: CLI_ARGS.compression.unwrap_or(header.compression),
+			..header.save_options()
+		};
+		if let Some(hash_algorithm) = CLI_ARGS.hash_algorithm {
+			if hash_algorithm != index.hash_algorithm {
+				anyhow::bail!(
+					"{} was created with {}, it can't be used with {hash_algorithm}",
+					escaped(&CLI_ARGS.index),
+					index.hash_algorithm
+				);
+			}
+		}
+		if !index.complete || CLI_ARGS.rescan {
+			let _scan_guard = ScanGuard::start();
+			if index.complete {
+				info!("Rescanning with {} threads...", CLI_ARGS.jobs);
+			} else if header.format_version == 0 {
+				info!("Rescanning to fill in what the old index didn't have...");
+			} else {
+				info!("The last scan didn't finish, picking up where it left off...");
+			}
+			index.rescan(&mut |index| write_index(index, save_options))?;
+			index.print_error_summary();
+			write_index(&index, save_options)?;
+		} else if header.format_version < index_file::FORMAT_VERSION || save_options.compression != header.compression {
+			// Saving it now means it only has to be upgraded once
+			write_index(&index, save_options)?;
+		}
 	}
 	stop_file_closer_thread();
 
@@ -238,14 +438,12 @@ fn main() -> anyhow::Result<()> {
 	let mut input = String::new();
 	loop {
 		input.clear();
-		let cwd_as_path: Arc<Path> = cwd.as_path().into();
-		let cwd_as_str = cwd_as_path.to_string_lossy();
-		if !index.paths_to_items.contains_key(&*cwd_as_path.to_string_lossy()) {
+		if !index.paths_to_items.contains_key(cwd.as_os_str()) {
 			println!("Going back to :root cuz the requested folder hasn't been explored.");
 			cwd = PathBuf::from(":root");
 			continue;
 		}
-		print!("fdupe {} > ", cwd.to_string_lossy());
+		print!("fdupe {} > ", escaped(&cwd));
 		std::io::stdout().flush()?;
 		std::io::stdin().read_line(&mut input)?;
 		match commands().run_inner(space_seperation(&input).as_slice()) {
@@ -263,15 +461,29 @@ fn main() -> anyhow::Result<()> {
 					println!("quit!");
 					break;
 				},
-				Commands::Ls { duplicates, recursive } => {
+				Commands::Ls {
+					duplicates,
+					recursive,
+					min_size,
+					max_size,
+				} => {
+					let size_range = SizeRange {
+						min: min_size,
+						max: max_size,
+					};
 					if recursive {
 						for (file_path_str, file_item) in index.paths_to_items.iter() {
-							//let file_path_str = file_path.to_string_lossy();
 							match file_item {
-								FileIndexItem::File { hash } => {
-									let dupe_count = index.file_instance_count(hash);
-									if !duplicates || dupe_count > 1 {
-										println!("F({}) {file_path_str}", index.file_instance_count(hash));
+								FileIndexItem::File { hash, .. } => {
+									let copy_count = index.physical_copy_count(hash);
+									let link_count = index.file_instance_count(hash) - copy_count;
+									if (!duplicates || copy_count > 1 || link_count > 0)
+										&& size_range.contains(hash.file_len)
+									{
+										println!(
+											"F({}) {file_path_str}",
+											dupe_count_label(hash, copy_count, link_count)
+										);
 									}
 								},
 								FileIndexItem::Folder { contents } => {
@@ -279,27 +491,37 @@ fn main() -> anyhow::Result<()> {
 										println!("D({}) {file_path_str}", contents.len());
 									}
 								},
+								FileIndexItem::Symlink { target } => {
+									if !duplicates {
+										println!("L {file_path_str} -> {target}");
+									}
+								},
 							}
 						}
 					} else {
-						match index.paths_to_items.get(&*cwd_as_str) {
+						match index.paths_to_items.get(cwd.as_os_str()) {
 							Some(FileIndexItem::Folder { contents }) => {
-								for file_path_str in contents.iter() {
-									let file_path = Path::new(file_path_str.as_ref());
-									let Some(file_item) = index.paths_to_items.get(file_path_str) else {
+								for file_path in contents.iter() {
+									let Some(file_item) = index.paths_to_items.get(file_path) else {
 										continue;
 									};
 
-									let file_path_str = if cwd.to_string_lossy() == ":root" {
-										file_path.to_string_lossy()
+									let file_path_str = if cwd == Path::new(":root") {
+										escaped(file_path.as_path())
 									} else {
-										file_path.file_name().unwrap_or_default().to_string_lossy()
+										escaped(file_path.as_path().file_name().unwrap_or_default().as_ref())
 									};
 									match file_item {
-										FileIndexItem::File { hash } => {
-											let dupe_count = index.file_instance_count(hash);
-											if !duplicates || dupe_count > 1 {
-												println!("F({}) {file_path_str}", index.file_instance_count(hash));
+										FileIndexItem::File { hash, .. } => {
+											let copy_count = index.physical_copy_count(hash);
+											let link_count = index.file_instance_count(hash) - copy_count;
+											if (!duplicates || copy_count > 1 || link_count > 0)
+												&& size_range.contains(hash.file_len)
+											{
+												println!(
+													"F({}) {file_path_str}",
+													dupe_count_label(hash, copy_count, link_count)
+												);
 											}
 										},
 										FileIndexItem::Folder { contents } => {
@@ -307,6 +529,11 @@ fn main() -> anyhow::Result<()> {
 												println!("D({}) {file_path_str}", contents.len());
 											}
 										},
+										FileIndexItem::Symlink { target } => {
+											if !duplicates {
+												println!("L {file_path_str} -> {target}");
+											}
+										},
 									}
 								}
 							},
@@ -317,55 +544,79 @@ fn main() -> anyhow::Result<()> {
 					}
 				},
 				Commands::Info { file } => {
-					let full_path: Arc<Path> = cwd.join(&file).as_path().into();
-					let full_path_str = &*full_path.to_string_lossy();
-					match index.paths_to_items.get(full_path_str) {
+					let full_path = cwd.join(file.as_path());
+					match index.paths_to_items.get(full_path.as_os_str()) {
 						Some(item) => {
-							println!("# Information about {}:", full_path.to_string_lossy());
+							println!("# Information about {}:", escaped(&full_path));
 							match item {
-								FileIndexItem::File { hash } => {
+								FileIndexItem::File { hash, meta } => {
 									let mut dupes = index.hash_to_paths.get(hash).cloned().unwrap_or_default();
-									dupes.remove(full_path_str);
-									println!("File with {} duplicates", dupes.len());
+									dupes.remove(full_path.as_os_str());
+									let (links, dupes): (Vec<_>, Vec<_>) = dupes
+										.into_iter()
+										.partition(|dupe| index.file_id(dupe) == Some(meta.file_id()));
+									if hash.is_unstable() {
+										println!("File kept changing while it was being hashed, it won't be deleted");
+									} else if hash.is_confirmed() || dupes.is_empty() {
+										println!("File with {} duplicates", dupes.len());
+									} else {
+										println!(
+											"File with {} probable duplicates (only the first and last blocks were compared)",
+											dupes.len()
+										);
+									}
 									for dupe in dupes {
 										println!(" -  {}", dupe);
 									}
+									if !links.is_empty() {
+										println!("File has {} other hardlinks", links.len());
+										for link in links {
+											println!(" -  {}", link);
+										}
+									}
 								},
 								FileIndexItem::Folder { contents } => {
-									println!(
-										"Directory with {} items. enter \"cd {}\" to view",
-										contents.len(),
-										file.to_string_lossy()
-									)
+									println!("Directory with {} items. enter \"cd {file}\" to view", contents.len());
+									if let Some(volume) = index.roots.get(full_path.as_os_str()) {
+										println!("Indexed root on {volume}");
+									}
+									if let Some((_, paths)) = index
+										.folder_duplicates(full_path.as_os_str())
+										.filter(|(_, paths)| paths.len() > 1)
+									{
+										println!("Everything in it is duplicated in {} other folders", paths.len() - 1);
+										for path in paths.iter().filter(|path| path.as_path() != full_path) {
+											println!(" -  {}", path);
+										}
+									}
+								},
+								FileIndexItem::Symlink { target } => {
+									println!("Symbolic link to {target}")
 								},
 							}
 						},
 						None => {
-							println!("{}: No such file or directory", full_path.to_string_lossy())
+							println!("{}: No such file or directory", escaped(&full_path))
 						},
 					}
 				},
 				Commands::Cd { dir } => {
-					if dir.to_string_lossy() == ".." {
+					if dir.as_bytes() == b".." {
 						cwd.pop();
 					} else {
-						let new_dir = cwd.join(dir);
-						if index
-							.paths_to_items
-							.get(&*new_dir.as_path().to_string_lossy())
-							.is_some()
-						{
+						let new_dir = cwd.join(dir.as_path());
+						if index.paths_to_items.contains_key(new_dir.as_os_str()) {
 							cwd = new_dir;
 						} else {
-							println!("{}: No such file or directory", new_dir.to_string_lossy())
+							println!("{}: No such file or directory", escaped(&new_dir))
 						}
 					}
 				},
 				Commands::Rmedir { dir } => {
-					let new_dir = cwd.join(dir);
+					let new_dir = cwd.join(dir.as_path());
 					println!(
 						"Confirm (y/N) removal of empty directories within {}",
-						new_dir.to_string_lossy()
+						escaped(&new_dir)
 					);
 					input.clear();
 					std::io::stdin().read_line(&mut input)?;
@@ -374,38 +625,91 @@ fn main() -> anyhow::Result<()> {
 					}
 					index.remove_empty_directories(&new_dir)?;
 				},
-				Commands::Rmodupes { dir } => {
-					let new_dir = cwd.join(dir);
+				Commands::Rmodupes { dir, hardlinks } => {
+					let new_dir = cwd.join(dir.as_path());
 					println!(
 						"Confirm (y/N) removal of ALL duplicates of files within {} FROM ALL OTHER FOLDERS",
-						new_dir.to_string_lossy()
+						escaped(&new_dir)
 					);
 					input.clear();
 					std::io::stdin().read_line(&mut input)?;
 					if input.chars().next().map(|c: char| char::to_ascii_lowercase(&c)) != Some('y') {
 						continue;
 					}
-					index.remove_dupes_in_other_folders(&new_dir)?;
+					index.remove_dupes_in_other_folders(&new_dir, hardlinks)?;
 				},
-				Commands::Rmdupes { dir } => {
-					let new_dir = cwd.join(dir);
+				Commands::Rmdupes { dir, hardlinks } => {
+					let new_dir = cwd.join(dir.as_path());
 					println!(
 						"Confirm (y/N) removal of ALL duplicates of files within {} FROM WITHIN THIS FOLDER",
-						new_dir.to_string_lossy()
+						escaped(&new_dir)
 					);
 					input.clear();
 					std::io::stdin().read_line(&mut input)?;
 					if input.chars().next().map(|c: char| char::to_ascii_lowercase(&c)) != Some('y') {
 						continue;
 					}
-					index.remove_dupes_from_folder(&new_dir)?;
+					index.remove_dupes_from_folder(&new_dir, hardlinks)?;
+				},
+				Commands::Errors => {
+					if index.errors.is_empty() {
+						println!("Nothing went wrong during the last scan");
+					}
+					for (path, error) in index.errors.iter() {
+						println!("[{}] {path}: {}", error.kind, error.message);
+					}
+				},
+				Commands::Dupdirs { min_size } => {
+					let groups = index.duplicate_folders();
+					if groups.is_empty() {
+						println!("No duplicate folders");
+					}
+					for (folder_hash, paths) in groups {
+						if min_size.is_some_and(|min_size| folder_hash.total_len < min_size.0) {
+							continue;
+						}
+						println!(
+							"{} copies{} of {} files ({}):",
+							paths.len(),
+							if folder_hash.confirmed { "" } else { "?" },
+							folder_hash.file_count,
+							format_bytes(folder_hash.total_len)
+						);
+						for path in paths {
+							println!(" -  {}", path);
+						}
+					}
+				},
+				Commands::Overlap { min, limit } => {
+					let overlaps = overlap::folder_overlaps(&index, min / 100.0);
+					if overlaps.is_empty() {
+						println!("No folders overlap by at least {min}%");
+					}
+					for overlap in overlaps.iter().take(limit) {
+						let [folder_a, folder_b] = overlap.folders;
+						match overlap.superset() {
+							_ if overlap.same_files() => println!("{folder_a} <-> {folder_b} (same files)"),
+							Some(superset) => println!("{folder_a} <-> {folder_b} ({superset} has everything)"),
+							None => println!("{folder_a} <-> {folder_b}"),
+						}
+						for (side, other) in [(0, folder_b), (1, folder_a)] {
+							println!(
+								"   {:.1}% of {} is in {other} ({} of {}, {} of {} files)",
+								overlap.fraction(side) * 100.0,
+								overlap.folders[side],
+								format_bytes(overlap.shared_bytes[side]),
+								format_bytes(overlap.total_bytes[side]),
+								overlap.shared_files[side],
+								overlap.total_files[side]
+							);
+						}
+					}
+					if overlaps.len() > limit {
+						println!("...and {} more, use --limit to see them", overlaps.len() - limit);
+					}
 				},
 				Commands::SaveIndex => {
-					println!("Writing index file...");
-					index_file.rewind()?;
-					borsh::to_writer(&mut index_file, &index)?;
-					println!("Saving file...");
-					index_file.flush()?;
+					write_index(&index, save_options)?;
 				},
 			},
 			Err(err) => match err {
@@ -0,0 +1,178 @@
+use std::{
+	fmt, fs,
+	os::unix::fs::{FileTypeExt, MetadataExt},
+	path::Path,
+	sync::Arc,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Which volume an indexed root lives on
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct VolumeInfo {
+	pub device: u64,
+	pub mount_point: Option<Arc<str>>,
+	pub fs_type: Option<Arc<str>>,
+	pub source: Option<Arc<str>>,
+	pub uuid: Option<Arc<str>>,
+}
+
+/// Same encoding as glibc's `major()` and `minor()`
+pub fn dev_major_minor(device: u64) -> (u64, u64) {
+	let major = ((device >> 8) & 0xfff) | ((device >> 32) & !0xfff);
+	let minor = (device & 0xff) | ((device >> 12) & !0xff);
+	(major, minor)
+}
+
+/// mountinfo escapes spaces and such as octal
+fn unescape_mountinfo(field: &str) -> String {
+	let mut result = String::with_capacity(field.len());
+	let mut chars = field.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			result.push(c);
+			continue;
+		}
+		let octal = chars.by_ref().take(3).collect::<String>();
+		match u8::from_str_radix(&octal, 8) {
+			Ok(byte) => result.push(byte as char),
+			Err(_) => {
+				result.push(c);
+				result.push_str(&octal);
+			},
+		}
+	}
+	result
+}
+
+/// The fields of a /proc/self/mountinfo line that we care about
+struct MountEntry {
+	/// As "major:minor"
+	device: String,
+	mount_point: String,
+	fs_type: String,
+	source: String,
+}
+
+/// Everything that's mounted, or nothing if mountinfo can't be read
+fn read_mountinfo() -> Vec<MountEntry> {
+	let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") else {
+		return Vec::new();
+	};
+	mountinfo
+		.lines()
+		.filter_map(|line| {
+			let (mount_fields, fs_fields) = line.split_once(" - ")?;
+			let mount_fields = mount_fields.split(' ').collect::<Vec<_>>();
+			let fs_fields = fs_fields.split(' ').collect::<Vec<_>>();
+			if mount_fields.len() < 5 || fs_fields.len() < 2 {
+				return None;
+			}
+			Some(MountEntry {
+				device: mount_fields[2].into(),
+				mount_point: unescape_mountinfo(mount_fields[4]),
+				fs_type: fs_fields[0].into(),
+				source: unescape_mountinfo(fs_fields[1]),
+			})
+		})
+		.collect()
+}
+
+/// Finds the UUID symlink in /dev/disk/by-uuid which points to the block device with this device number
+fn find_uuid(device: u64) -> Option<Arc<str>> {
+	for entry in fs::read_dir("/dev/disk/by-uuid").ok()? {
+		let Ok(entry) = entry else {
+			continue;
+		};
+		let Ok(metadata) = fs::metadata(entry.path()) else {
+			continue;
+		};
+		if metadata.file_type().is_block_device() && metadata.rdev() == device {
+			return Some(entry.file_name().to_string_lossy().into());
+		}
+	}
+	None
+}
+
+/// The block device a filesystem is really on. Btrfs gives every subvolume its own anonymous device number which
+/// doesn't exist in sysfs, so whatever is mounted with that number is looked up to find its source instead. `None` if
+/// the filesystem isn't on a block device at all, like network and virtual filesystems.
+pub fn backing_device(device: u64) -> Option<u64> {
+	let (major, minor) = dev_major_minor(device);
+	if Path::new(&format!("/sys/dev/block/{major}:{minor}")).exists() {
+		return Some(device);
+	}
+	let device_str = format!("{major}:{minor}");
+	read_mountinfo()
+		.into_iter()
+		.filter(|entry| entry.device == device_str)
+		.find_map(|entry| {
+			let metadata = fs::metadata(&entry.source).ok()?;
+			metadata.file_type().is_block_device().then(|| metadata.rdev())
+		})
+}
+
+/// Whether the block device is a spinning disk, going by its queue/rotational flag in sysfs. Partitions don't have a
+/// queue of their own, so the disk they're on is checked instead. `None` if it couldn't be found, which is the case for
+/// network and virtual filesystems.
+pub fn is_rotational(device: u64) -> Option<bool> {
+	let (major, minor) = dev_major_minor(device);
+	let sys_path = fs::canonicalize(format!("/sys/dev/block/{major}:{minor}")).ok()?;
+	for dir in [sys_path.as_path(), sys_path.parent()?] {
+		if let Ok(rotational) = fs::read_to_string(dir.join("queue/rotational")) {
+			return Some(rotational.trim() == "1");
+		}
+	}
+	None
+}
+
+impl VolumeInfo {
+	/// Anything other than the device number is best-effort, and will be `None` if it couldn't be found.
+	pub fn from_path(path: &Path) -> std::io::Result<Self> {
+		let device = fs::metadata(path)?.dev();
+		let (major, minor) = dev_major_minor(device);
+		let device_str = format!("{major}:{minor}");
+		let mut result = Self {
+			device,
+			mount_point: None,
+			fs_type: None,
+			source: None,
+			uuid: find_uuid(device),
+		};
+		// Bind mounts can make the same device show up more than once, the closest mount point to the path wins.
+		let mut best_mount_point_len = 0;
+		for entry in read_mountinfo() {
+			if entry.device != device_str
+				|| !path.starts_with(&entry.mount_point)
+				|| entry.mount_point.len() < best_mount_point_len
+			{
+				continue;
+			}
+			best_mount_point_len = entry.mount_point.len();
+			result.mount_point = Some(entry.mount_point.into());
+			result.fs_type = Some(entry.fs_type.into());
+			result.source = Some(entry.source.into());
+		}
+		Ok(result)
+	}
+}
+
+impl fmt::Display for VolumeInfo {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let (major, minor) = dev_major_minor(self.device);
+		write!(f, "device {major}:{minor}")?;
+		if let Some(source) = &self.source {
+			write!(f, " ({source})")?;
+		}
+		if let Some(fs_type) = &self.fs_type {
+			write!(f, ", {fs_type}")?;
+		}
+		if let Some(mount_point) = &self.mount_point {
+			write!(f, " mounted at {mount_point}")?;
+		}
+		if let Some(uuid) = &self.uuid {
+			write!(f, ", UUID {uuid}")?;
+		}
+		Ok(())
+	}
+}
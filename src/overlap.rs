@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use crate::{
+	index_path::IndexPath,
+	indexer::{FileHash, FileIndex, FileIndexItem},
+};
+
+/// Files with more copies than this don't pair folders up by themselves. They tend to be things like licenses and
+/// empty `__init__.py` files, which turn up in all sorts of unrelated folders, and pairing up every folder they're in
+/// takes time and memory quadratic in the number of copies. They're still counted in pairs that other files make.
+const MAX_PAIRING_COPIES: usize = 32;
+
+/// How much two folders have in common. Everything is counted from both sides, `shared_files[0]` is how many of the
+/// files in `folders[0]` have a copy somewhere in `folders[1]`.
+#[derive(Debug, Clone)]
+pub struct FolderOverlap<'a> {
+	pub folders: [&'a IndexPath; 2],
+	pub shared_files: [u64; 2],
+	pub shared_bytes: [u64; 2],
+	pub total_files: [u64; 2],
+	pub total_bytes: [u64; 2],
+}
+impl FolderOverlap<'_> {
+	/// How much of one of the folders is also in the other one, by bytes
+	pub fn fraction(&self, side: usize) -> f64 {
+		if self.total_bytes[side] == 0 {
+			return 0.0;
+		}
+		self.shared_bytes[side] as f64 / self.total_bytes[side] as f64
+	}
+	/// Whichever side is the most covered by the other one
+	pub fn score(&self) -> f64 {
+		self.fraction(0).max(self.fraction(1))
+	}
+	/// Whether both folders have a copy of every file in the other one
+	pub fn same_files(&self) -> bool {
+		self.shared_files == self.total_files
+	}
+	/// The folder which has a copy of every file in the other one, if there is one
+	pub fn superset(&self) -> Option<&IndexPath> {
+		if self.shared_files[1] == self.total_files[1] {
+			Some(self.folders[0])
+		} else if self.shared_files[0] == self.total_files[0] {
+			Some(self.folders[1])
+		} else {
+			None
+		}
+	}
+}
+
+/// Every folder the path is in, as long as it's part of the index
+fn indexed_ancestors<'a>(index: &'a FileIndex, path: &IndexPath) -> Vec<&'a IndexPath> {
+	let mut ancestors = Vec::new();
+	for ancestor in path.as_path().ancestors().skip(1) {
+		match index.paths_to_items.get_key_value(ancestor.as_os_str()) {
+			Some((ancestor, FileIndexItem::Folder { .. })) => ancestors.push(ancestor),
+			_ => break,
+		}
+	}
+	ancestors
+}
+
+fn is_nested(a: &IndexPath, b: &IndexPath) -> bool {
+	a.as_path().starts_with(b.as_path()) || b.as_path().starts_with(a.as_path())
+}
+
+type FolderPair<'a> = (&'a IndexPath, &'a IndexPath);
+
+fn folder_overlap<'a>(
+	(folder_a, folder_b): FolderPair<'a>,
+	shared: &BTreeMap<FolderPair<'a>, ([u64; 2], [u64; 2])>,
+	totals: &BTreeMap<&'a IndexPath, (u64, u64)>,
+) -> Option<FolderOverlap<'a>> {
+	let (shared_files, shared_bytes) = shared.get(&(folder_a, folder_b))?;
+	let total = |folder| totals.get(folder).copied().unwrap_or_default();
+	let (total_a, total_b) = (total(folder_a), total(folder_b));
+	Some(FolderOverlap {
+		folders: [folder_a, folder_b],
+		shared_files: *shared_files,
+		shared_bytes: *shared_bytes,
+		total_files: [total_a.0, total_b.0],
+		total_bytes: [total_a.1, total_b.1],
+	})
+}
+
+/// Compares every pair of folders which have files in common. Only files which were confirmed to be duplicates by a
+/// full hash count as shared, and folders are never compared with their own sub-folders. Folders which only share
+/// files with more than [MAX_PAIRING_COPIES] copies aren't compared at all.
+///
+/// Pairs scoring below `min_score` are left out. So are pairs where the folder around one of them is in the other one
+/// just as much, or where the folders around both of them are in each other just as much, since the outer pair
+/// already says the same thing. The best pairs come first.
+pub fn folder_overlaps(index: &FileIndex, min_score: f64) -> Vec<FolderOverlap<'_>> {
+	let mut totals: BTreeMap<&IndexPath, (u64, u64)> = BTreeMap::new();
+	for (path, item) in index.paths_to_items.iter() {
+		let Some(hash) = item.as_file() else {
+			continue;
+		};
+		for ancestor in indexed_ancestors(index, path) {
+			let (files, bytes) = totals.entry(ancestor).or_default();
+			*files += 1;
+			*bytes += hash.file_len;
+		}
+	}
+
+	// Keyed by the two folders in order, so each pair is only counted once
+	let mut shared: BTreeMap<FolderPair, ([u64; 2], [u64; 2])> = BTreeMap::new();
+	// How many copies of each of the files with too many copies every folder has, in the same order as hash_to_paths
+	let mut common_copies: BTreeMap<&IndexPath, Vec<(&FileHash, u64)>> = BTreeMap::new();
+	for (hash, paths) in index.hash_to_paths.iter() {
+		if !hash.is_confirmed() || paths.len() < 2 {
+			continue;
+		}
+		// How many copies of this file each folder has
+		let mut copy_counts: BTreeMap<&IndexPath, u64> = BTreeMap::new();
+		for path in paths {
+			for ancestor in indexed_ancestors(index, path) {
+				*copy_counts.entry(ancestor).or_default() += 1;
+			}
+		}
+		if paths.len() > MAX_PAIRING_COPIES {
+			for (folder, count) in copy_counts {
+				common_copies.entry(folder).or_default().push((hash, count));
+			}
+			continue;
+		}
+		let copy_counts = copy_counts.into_iter().collect::<Vec<_>>();
+		for (i, &(folder_a, count_a)) in copy_counts.iter().enumerate() {
+			for &(folder_b, count_b) in &copy_counts[i + 1..] {
+				if is_nested(folder_a, folder_b) {
+					continue;
+				}
+				let (files, bytes) = shared.entry((folder_a, folder_b)).or_default();
+				files[0] += count_a;
+				files[1] += count_b;
+				bytes[0] += count_a * hash.file_len;
+				bytes[1] += count_b * hash.file_len;
+			}
+		}
+	}
+
+	for ((folder_a, folder_b), (files, bytes)) in shared.iter_mut() {
+		let (Some(copies_a), Some(copies_b)) = (common_copies.get(folder_a), common_copies.get(folder_b)) else {
+			continue;
+		};
+		// Both are sorted by hash, so they can be walked through together
+		let mut copies_b = copies_b.iter().peekable();
+		for &(hash, count_a) in copies_a {
+			while copies_b.next_if(|(hash_b, _)| *hash_b < hash).is_some() {}
+			if let Some(&(_, count_b)) = copies_b.next_if(|(hash_b, _)| *hash_b == hash) {
+				files[0] += count_a;
+				files[1] += count_b;
+				bytes[0] += count_a * hash.file_len;
+				bytes[1] += count_b * hash.file_len;
+			}
+		}
+	}
+
+	let pair_overlap = |pair| folder_overlap(pair, &shared, &totals);
+	let parent = |folder: &IndexPath| {
+		let parent = folder.as_path().parent()?;
+		index
+			.paths_to_items
+			.get_key_value(parent.as_os_str())
+			.map(|(parent, _)| parent)
+	};
+	// How much of `folder` is also in `other`, pairs are only stored one way around
+	let coverage = |folder, other| match pair_overlap((folder, other)) {
+		Some(overlap) => Some(overlap.fraction(0)),
+		None => pair_overlap((other, folder)).map(|overlap| overlap.fraction(1)),
+	};
+	let mut overlaps = shared
+		.keys()
+		.filter_map(|&pair| pair_overlap(pair))
+		.filter(|overlap| overlap.score() >= min_score)
+		.filter(|overlap| {
+			let [folder_a, folder_b] = overlap.folders;
+			// If the folder around this one is just as much in the other folder, it says the same thing
+			let has_better_parent = |folder, other| {
+				let Some(parent) = parent(folder) else {
+					return false;
+				};
+				coverage(parent, other)
+					.is_some_and(|parent_coverage| parent_coverage >= coverage(folder, other).unwrap_or_default())
+			};
+			// Same for a pair of sub-folders of two folders which are in each other just as much
+			let has_better_parents = parent(folder_a)
+				.zip(parent(folder_b))
+				.is_some_and(|(parent_a, parent_b)| {
+					coverage(parent_a, parent_b).is_some_and(|coverage_a| coverage_a >= overlap.fraction(0))
+						&& coverage(parent_b, parent_a).is_some_and(|coverage_b| coverage_b >= overlap.fraction(1))
+				});
+			!has_better_parent(folder_a, folder_b) && !has_better_parent(folder_b, folder_a) && !has_better_parents
+		})
+		.collect::<Vec<_>>();
+	overlaps.sort_by(|a, b| {
+		b.score()
+			.total_cmp(&a.score())
+			.then_with(|| b.shared_bytes.iter().sum::<u64>().cmp(&a.shared_bytes.iter().sum()))
+	});
+	overlaps
+}
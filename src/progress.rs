@@ -0,0 +1,243 @@
+use std::{
+	fmt,
+	io::{IsTerminal, Write},
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, LazyLock, Mutex, PoisonError,
+	},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant},
+};
+
+use crate::CLI_ARGS;
+
+/// How often the progress line is redrawn when stdout is a terminal
+const TTY_REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+/// How often a progress line is logged when stdout isn't a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	Warning,
+	Info,
+	Verbose,
+}
+fn max_level() -> Level {
+	if CLI_ARGS.verbose {
+		Level::Verbose
+	} else if CLI_ARGS.quiet {
+		Level::Warning
+	} else {
+		Level::Info
+	}
+}
+
+/// What's being worked on right now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+	Scanning,
+	PartialHashing,
+	Hashing,
+}
+impl fmt::Display for Stage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Scanning => "Scanning",
+			Self::PartialHashing => "Partially hashing",
+			Self::Hashing => "Hashing",
+		})
+	}
+}
+
+#[derive(Debug)]
+struct Counters {
+	files_found: AtomicU64,
+	dirs_found: AtomicU64,
+	files_done: AtomicU64,
+	files_total: AtomicU64,
+	bytes_done: AtomicU64,
+	bytes_total: AtomicU64,
+}
+static COUNTERS: Counters = Counters {
+	files_found: AtomicU64::new(0),
+	dirs_found: AtomicU64::new(0),
+	files_done: AtomicU64::new(0),
+	files_total: AtomicU64::new(0),
+	bytes_done: AtomicU64::new(0),
+	bytes_total: AtomicU64::new(0),
+};
+/// Whether the progress line is on screen, anything else being printed has to clear it first. Also makes sure the
+/// reporter and log messages don't write over each other.
+static LINE_SHOWN: Mutex<bool> = Mutex::new(false);
+static IS_TTY: LazyLock<bool> = LazyLock::new(|| std::io::stdout().is_terminal());
+
+pub fn add_found(dirs: u64, files: u64) {
+	COUNTERS.dirs_found.fetch_add(dirs, Ordering::Relaxed);
+	COUNTERS.files_found.fetch_add(files, Ordering::Relaxed);
+}
+/// Files and bytes which are going to be hashed in the current stage
+pub fn add_total(files: u64, bytes: u64) {
+	COUNTERS.files_total.fetch_add(files, Ordering::Relaxed);
+	COUNTERS.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+}
+pub fn add_bytes_done(bytes: u64) {
+	COUNTERS.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+}
+pub fn add_file_done() {
+	COUNTERS.files_done.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Prints a message, clearing the progress line first if it's showing. Warnings go to stderr.
+pub fn log(level: Level, args: fmt::Arguments) {
+	if level > max_level() {
+		return;
+	}
+	let mut line_shown = LINE_SHOWN.lock().unwrap_or_else(PoisonError::into_inner);
+	if *line_shown {
+		print!("\r\x1b[K");
+		let _ = std::io::stdout().flush();
+		*line_shown = false;
+	}
+	if level == Level::Warning {
+		eprintln!("{args}");
+	} else {
+		println!("{args}");
+	}
+}
+macro_rules! warning {
+	($($arg:tt)*) => {
+		$crate::progress::log($crate::progress::Level::Warning, format_args!($($arg)*))
+	};
+}
+macro_rules! info {
+	($($arg:tt)*) => {
+		$crate::progress::log($crate::progress::Level::Info, format_args!($($arg)*))
+	};
+}
+macro_rules! verbose {
+	($($arg:tt)*) => {
+		$crate::progress::log($crate::progress::Level::Verbose, format_args!($($arg)*))
+	};
+}
+pub(crate) use {info, verbose, warning};
+
+/// Formats a number of bytes with a binary unit, like "1.5 GiB"
+pub fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+	let mut value = bytes as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	if unit == 0 {
+		format!("{bytes} B")
+	} else {
+		format!("{value:.1} {}", UNITS[unit])
+	}
+}
+
+fn format_duration(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn status_line(stage: Stage, started: Instant) -> String {
+	let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+	if stage == Stage::Scanning {
+		return format!(
+			"{stage}: {} files in {} directories",
+			load(&COUNTERS.files_found),
+			load(&COUNTERS.dirs_found)
+		);
+	}
+	let (bytes_done, bytes_total) = (load(&COUNTERS.bytes_done), load(&COUNTERS.bytes_total));
+	let elapsed = started.elapsed();
+	let throughput = bytes_done as f64 / elapsed.as_secs_f64().max(0.001);
+	let mut line = format!(
+		"{stage}: {}/{} files, {}/{}, {}/s",
+		load(&COUNTERS.files_done),
+		load(&COUNTERS.files_total),
+		format_bytes(bytes_done),
+		format_bytes(bytes_total),
+		format_bytes(throughput as u64)
+	);
+	if throughput > 0.0 && bytes_total > bytes_done {
+		let eta = Duration::from_secs_f64((bytes_total - bytes_done) as f64 / throughput);
+		line.push_str(&format!(", ETA {}", format_duration(eta)));
+	}
+	line
+}
+
+fn show_status(stage: Stage, started: Instant) {
+	let mut line_shown = LINE_SHOWN.lock().unwrap_or_else(PoisonError::into_inner);
+	if *IS_TTY {
+		print!("\r\x1b[K{}", status_line(stage, started));
+		let _ = std::io::stdout().flush();
+		*line_shown = true;
+	} else {
+		println!("{}", status_line(stage, started));
+	}
+}
+
+/// Shows progress until it's dropped, at which point the final numbers are printed
+#[derive(Debug)]
+pub struct ProgressReporter {
+	stage: Stage,
+	started: Instant,
+	stop: Arc<AtomicBool>,
+	reporter_thread: Option<JoinHandle<()>>,
+}
+impl ProgressReporter {
+	pub fn start(stage: Stage) -> Self {
+		for counter in [
+			&COUNTERS.files_done,
+			&COUNTERS.files_total,
+			&COUNTERS.bytes_done,
+			&COUNTERS.bytes_total,
+		] {
+			counter.store(0, Ordering::Relaxed);
+		}
+		let started = Instant::now();
+		let stop = Arc::new(AtomicBool::new(false));
+		let reporter_thread = (max_level() >= Level::Info).then(|| {
+			let stop = stop.clone();
+			let interval = if *IS_TTY { TTY_REFRESH_INTERVAL } else { LOG_INTERVAL };
+			thread::Builder::new()
+				.name("Progress".into())
+				.spawn(move || {
+					let mut last_shown = Instant::now();
+					while !stop.load(Ordering::Relaxed) {
+						// Sleeping in short bursts so stopping doesn't have to wait for a whole interval
+						thread::sleep(TTY_REFRESH_INTERVAL);
+						if last_shown.elapsed() >= interval {
+							show_status(stage, started);
+							last_shown = Instant::now();
+						}
+					}
+				})
+				.unwrap()
+		});
+		Self {
+			stage,
+			started,
+			stop,
+			reporter_thread,
+		}
+	}
+}
+impl Drop for ProgressReporter {
+	fn drop(&mut self) {
+		let Some(reporter_thread) = self.reporter_thread.take() else {
+			return;
+		};
+		self.stop.store(true, Ordering::Relaxed);
+		let _ = reporter_thread.join();
+		show_status(self.stage, self.started);
+		let mut line_shown = LINE_SHOWN.lock().unwrap_or_else(PoisonError::into_inner);
+		if *line_shown {
+			println!();
+			*line_shown = false;
+		}
+	}
+}
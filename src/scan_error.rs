@@ -0,0 +1,52 @@
+use std::{
+	fmt,
+	io::{Error as IoError, ErrorKind as IoErrorKind},
+	sync::Arc,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, BorshDeserialize, BorshSerialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum ScanErrorKind {
+	PermissionDenied = 0,
+	/// The path was deleted or moved while it was being indexed
+	Vanished = 1,
+	Io = 2,
+}
+impl ScanErrorKind {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::PermissionDenied => "permission denied",
+			Self::Vanished => "vanished",
+			Self::Io => "I/O error",
+		}
+	}
+}
+impl fmt::Display for ScanErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// Why a path couldn't be indexed. Scanning carries on past these, they're kept in the index so they can be looked at
+/// afterwards.
+#[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
+pub struct ScanError {
+	pub kind: ScanErrorKind,
+	pub message: Arc<str>,
+}
+impl From<&IoError> for ScanError {
+	fn from(err: &IoError) -> Self {
+		let kind = match err.kind() {
+			IoErrorKind::PermissionDenied => ScanErrorKind::PermissionDenied,
+			IoErrorKind::NotFound => ScanErrorKind::Vanished,
+			_ => ScanErrorKind::Io,
+		};
+		Self {
+			kind,
+			message: err.to_string().into(),
+		}
+	}
+}
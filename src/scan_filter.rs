@@ -0,0 +1,257 @@
+use std::{
+	os::unix::ffi::OsStrExt,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::{gitignore::Gitignore, Match};
+use regex::bytes::Regex;
+
+use crate::{index_path::escaped, progress::warning};
+
+/// Name of the optional per-directory ignore file, which uses gitignore syntax
+pub const IGNORE_FILE_NAME: &str = ".fdupesignore";
+
+/// A pattern given to `--include` or `--exclude`. Regexes are prefixed with "re:" and are matched against the full
+/// path. Anything else is a glob, which is matched against the file name if it doesn't contain a "/", otherwise it's
+/// matched against the full path. Every path that's scanned is absolute, so globs which don't start with a "/" can
+/// match at any depth, like they would in a gitignore file. A trailing "/" doesn't make a difference.
+#[derive(Debug, Clone)]
+pub enum PathPattern {
+	NameGlob(GlobMatcher),
+	PathGlob(GlobMatcher),
+	Regex(Regex),
+}
+impl PathPattern {
+	pub fn is_match(&self, path: &Path) -> bool {
+		match self {
+			Self::NameGlob(glob) => path.file_name().is_some_and(|file_name| glob.is_match(file_name)),
+			Self::PathGlob(glob) => glob.is_match(path),
+			Self::Regex(regex) => regex.is_match(path.as_os_str().as_bytes()),
+		}
+	}
+}
+impl FromStr for PathPattern {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(regex) = s.strip_prefix("re:") {
+			return Regex::new(regex).map(Self::Regex).map_err(|err| err.to_string());
+		}
+		let glob = s.strip_prefix("glob:").unwrap_or(s);
+		let glob = match (glob.strip_prefix("~/"), std::env::var_os("HOME")) {
+			(Some(rest), Some(home)) => PathBuf::from(home).join(rest).to_string_lossy().into_owned(),
+			_ => glob.to_string(),
+		};
+		let glob = glob.trim_end_matches('/');
+		let build = |glob: &str| {
+			GlobBuilder::new(glob)
+				.literal_separator(true)
+				.build()
+				.map(|glob| glob.compile_matcher())
+				.map_err(|err| err.to_string())
+		};
+		if !glob.contains('/') {
+			Ok(Self::NameGlob(build(glob)?))
+		} else if glob.starts_with('/') {
+			Ok(Self::PathGlob(build(glob)?))
+		} else {
+			Ok(Self::PathGlob(build(&format!("**/{glob}"))?))
+		}
+	}
+}
+
+/// Decides which paths make it into the index
+#[derive(Debug, Clone, Default)]
+pub struct ScanFilter {
+	/// If this isn't empty, files have to match at least one of these. Directories are always descended into.
+	pub include: Vec<PathPattern>,
+	/// Anything matching these is skipped, excluded directories aren't descended into.
+	pub exclude: Vec<PathPattern>,
+	/// Whether to read [IGNORE_FILE_NAME] in each directory
+	pub use_ignore_files: bool,
+	pub file_size: SizeRange,
+	pub empty_files: EmptyFilePolicy,
+}
+impl ScanFilter {
+	/// Reads the ignore file in a directory, if there is one and ignore files are being used.
+	pub fn read_ignore_file(&self, dir: &Path) -> Option<Gitignore> {
+		if !self.use_ignore_files {
+			return None;
+		}
+		let ignore_file_path = dir.join(IGNORE_FILE_NAME);
+		if !ignore_file_path.is_file() {
+			return None;
+		}
+		let (gitignore, err) = Gitignore::new(&ignore_file_path);
+		if let Some(err) = err {
+			warning!("{}: {err}", escaped(&ignore_file_path));
+		}
+		Some(gitignore)
+	}
+	/// Files can only be filtered by their length once they've been stat'd, so this is separate from
+	/// [ScanFilter::is_allowed].
+	pub fn is_file_len_allowed(&self, file_len: u64) -> bool {
+		self.file_size.contains(file_len) && !(file_len == 0 && self.empty_files == EmptyFilePolicy::Exclude)
+	}
+	/// `ignore_files` are the ignore files of each directory leading to this path, starting from the root.
+	pub fn is_allowed<'a>(
+		&self,
+		path: &Path,
+		is_dir: bool,
+		ignore_files: impl DoubleEndedIterator<Item = &'a Gitignore>,
+	) -> bool {
+		if self.exclude.iter().any(|pattern| pattern.is_match(path)) {
+			return false;
+		}
+		// Ignore files in deeper directories take precedence
+		for ignore_file in ignore_files.rev() {
+			match ignore_file.matched(path, is_dir) {
+				Match::None => {},
+				Match::Ignore(_) => return false,
+				Match::Whitelist(_) => break,
+			}
+		}
+		is_dir || self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(path))
+	}
+}
+
+/// A number of bytes, which can be written with a unit like "10K", "1.5GiB" or "200MB". Units with a "B" but without
+/// an "i" are powers of 1000, everything else is powers of 1024.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+impl FromStr for ByteSize {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let number_end = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+		let (number, unit) = s.split_at(number_end);
+		let number = number
+			.parse::<f64>()
+			.map_err(|_| format!("\"{s}\" isn't a valid size"))?;
+		let unit = unit.trim();
+		let (prefix, base) = if let Some(prefix) = unit.strip_suffix("iB") {
+			(prefix, 1024u64)
+		} else if let Some(prefix) = unit.strip_suffix('B').filter(|prefix| !prefix.is_empty()) {
+			(prefix, 1000u64)
+		} else {
+			(unit, 1024u64)
+		};
+		let exponent = match prefix.to_ascii_uppercase().as_str() {
+			"" | "B" => 0,
+			"K" => 1,
+			"M" => 2,
+			"G" => 3,
+			"T" => 4,
+			"P" => 5,
+			_ => return Err(format!("\"{unit}\" isn't a known size unit")),
+		};
+		Ok(Self((number * base.pow(exponent) as f64) as u64))
+	}
+}
+
+/// What to do with zero-length files, which would otherwise all be duplicates of each other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyFilePolicy {
+	/// Leave them out of the index
+	Exclude,
+	/// Index them, but never consider them duplicates of anything
+	#[default]
+	Unique,
+	/// Treat them like any other file
+	Normal,
+}
+impl FromStr for EmptyFilePolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"exclude" => Ok(Self::Exclude),
+			"unique" => Ok(Self::Unique),
+			"normal" => Ok(Self::Normal),
+			_ => Err(format!(
+				"unknown empty file policy \"{s}\", expected one of: exclude, unique, normal"
+			)),
+		}
+	}
+}
+
+/// Optional lower and upper bounds on file length, both inclusive
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeRange {
+	pub min: Option<ByteSize>,
+	pub max: Option<ByteSize>,
+}
+impl SizeRange {
+	pub fn contains(&self, file_len: u64) -> bool {
+		self.min.is_none_or(|min| file_len >= min.0) && self.max.is_none_or(|max| file_len <= max.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matches(pattern: &str, path: &str) -> bool {
+		pattern.parse::<PathPattern>().unwrap().is_match(Path::new(path))
+	}
+
+	#[test]
+	fn name_globs() {
+		assert!(matches("*.o", "/src/build/main.o"));
+		assert!(!matches("*.o", "/src/build/main.c"));
+		assert!(matches("node_modules", "/tmp/a/node_modules"));
+		assert!(!matches("node_modules", "/tmp/node_modules/x"));
+		assert!(matches("glob:*.tmp", "/a/b.tmp"));
+	}
+
+	#[test]
+	fn trailing_slash_is_ignored() {
+		assert!(matches("node_modules/", "/tmp/a/node_modules"));
+		assert!(matches("a/node_modules/", "/tmp/a/node_modules"));
+	}
+
+	#[test]
+	fn relative_path_globs_match_at_any_depth() {
+		assert!(matches("build/*.o", "/src/build/main.o"));
+		assert!(matches("build/*.o", "/build/main.o"));
+		assert!(!matches("build/*.o", "/src/build/sub/main.o"));
+		assert!(!matches("build/*.o", "/src/rebuild/main.o"));
+		assert!(matches("a/node_modules", "/tmp/a/node_modules"));
+		assert!(!matches("a/node_modules", "/tmp/b/node_modules"));
+	}
+
+	#[test]
+	fn absolute_path_globs() {
+		assert!(matches("/tmp/*/cache", "/tmp/a/cache"));
+		assert!(!matches("/tmp/*/cache", "/home/tmp/a/cache"));
+		assert!(!matches("/tmp/*/cache", "/tmp/a/b/cache"));
+		assert!(matches("/tmp/**/cache", "/tmp/a/b/cache"));
+	}
+
+	#[test]
+	fn regexes() {
+		assert!(matches(r"re:\.bak$", "/a/b.bak"));
+		assert!(!matches(r"re:\.bak$", "/a/b.bak/c"));
+		assert!("re:(".parse::<PathPattern>().is_err());
+	}
+
+	#[test]
+	fn byte_sizes() {
+		let size = |s: &str| s.parse::<ByteSize>().map(|size| size.0);
+		assert_eq!(size("512"), Ok(512));
+		assert_eq!(size("10K"), Ok(10 * 1024));
+		assert_eq!(size("10k"), Ok(10 * 1024));
+		assert_eq!(size("10KiB"), Ok(10 * 1024));
+		assert_eq!(size("200MB"), Ok(200_000_000));
+		assert_eq!(size("1.5GiB"), Ok(1024 * 1024 * 1024 * 3 / 2));
+		assert_eq!(size(" 2 T "), Ok(2 << 40));
+		assert_eq!(size("7B"), Ok(7));
+		assert!(size("").is_err());
+		assert!(size("K").is_err());
+		assert!(size("10X").is_err());
+		assert!(size("1.2.3M").is_err());
+	}
+}
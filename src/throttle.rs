@@ -0,0 +1,57 @@
+use std::{
+	io::Error as IoError,
+	sync::{Mutex, PoisonError},
+	thread,
+	time::{Duration, Instant},
+};
+
+use crate::{progress::warning, CLI_ARGS};
+
+/// When the next read is allowed to start, shared by every thread so the limit applies to the whole process
+static NEXT_READ: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Waits until reading `bytes` more wouldn't go over `--max-read-rate`. Every read gets its own slot of time, so
+/// reads from different threads are spread out instead of bursting all at once.
+pub fn throttle(bytes: u64) {
+	let Some(max_rate) = CLI_ARGS.max_read_rate.filter(|max_rate| max_rate.0 > 0) else {
+		return;
+	};
+	let start = {
+		let mut next_read = NEXT_READ.lock().unwrap_or_else(PoisonError::into_inner);
+		let now = Instant::now();
+		// Time that went unused doesn't carry over, otherwise a long pause would allow a burst afterwards
+		let start = next_read.map_or(now, |next_read| next_read.max(now));
+		*next_read = Some(start + Duration::from_secs_f64(bytes as f64 / max_rate.0 as f64));
+		start
+	};
+	thread::sleep(start.saturating_duration_since(Instant::now()));
+}
+
+// Not exported by libc, see ioprio_set(2)
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+/// Lowest CPU priority there is
+const LOW_PRIORITY_NICE: libc::c_int = 19;
+
+/// Puts the process in the idle I/O class, so it only gets to use the disk when nothing else wants it, and makes it
+/// as nice as possible. Both of these only apply to the calling thread and the ones it starts afterwards, so this has
+/// to be called before any other threads are spawned. Failing to change either of them isn't fatal.
+pub fn lower_priority() {
+	// SAFETY: these only change scheduling settings of the calling thread, no memory is passed to them
+	let ioprio_result = unsafe {
+		libc::syscall(
+			libc::SYS_ioprio_set,
+			IOPRIO_WHO_PROCESS,
+			0,
+			IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+		)
+	};
+	if ioprio_result == -1 {
+		warning!("couldn't set idle I/O priority: {}", IoError::last_os_error());
+	}
+	// SAFETY: same as above
+	if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICE) } == -1 {
+		warning!("couldn't lower CPU priority: {}", IoError::last_os_error());
+	}
+}
@@ -0,0 +1,81 @@
+use std::{
+	collections::BTreeMap,
+	fs::{self, File},
+	io::{ErrorKind as IoErrorKind, Read},
+	iter,
+	os::unix::fs::MetadataExt,
+	path::Path,
+};
+
+use crate::index_path::escaped;
+
+const COMPARE_BUF_SIZE: usize = 131072; // 128KiB
+
+/// Reads until `buf` is full or the end of the file is reached
+fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+	let mut total_read = 0;
+	while total_read < buf.len() {
+		match file.read(&mut buf[total_read..]) {
+			Ok(0) => break,
+			Ok(read_amount) => total_read += read_amount,
+			Err(e) if matches!(e.kind(), IoErrorKind::Interrupted) => {},
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(total_read)
+}
+
+/// Makes sure deleting `doomed` can't take the data in `kept` with it. Paths with the same (device, inode) aren't
+/// necessarily separate hardlinks, they might be the same directory entry reached through a symlink or a bind mount.
+/// Deleting one of those deletes the only copy, and comparing it with itself would always pass.
+pub fn verify_separate_links(kept: &Path, doomed: &[&Path]) -> anyhow::Result<()> {
+	let kept_canonical = kept.canonicalize()?;
+	// How many of the paths lead to each file, which can't be more than the number of links it really has
+	let mut path_counts: BTreeMap<(u64, u64), u64> = BTreeMap::new();
+	for path in iter::once(&kept).chain(doomed) {
+		if *path != kept && path.canonicalize()? == kept_canonical {
+			anyhow::bail!("{} is the same file as {}", escaped(path), escaped(kept));
+		}
+		let metadata = fs::metadata(path)?;
+		let path_count = path_counts.entry((metadata.dev(), metadata.ino())).or_default();
+		*path_count += 1;
+		if *path_count > metadata.nlink() {
+			anyhow::bail!(
+				"there are more paths to {} than it has links, some of them must be aliases of each other",
+				escaped(path)
+			);
+		}
+	}
+	Ok(())
+}
+
+/// Re-reads both files and makes sure they're still the length they were indexed with, and are identical
+/// byte-for-byte. Digests can collide and files can change after they're indexed, so this should always be done
+/// before deleting anything. A file always matches itself, so [verify_separate_links] has to be checked first.
+pub fn verify_identical(kept: &Path, doomed: &Path, expected_len: u64) -> anyhow::Result<()> {
+	let mut kept_file = File::open(kept)?;
+	let mut doomed_file = File::open(doomed)?;
+	for (path, file) in [(kept, &kept_file), (doomed, &doomed_file)] {
+		if file.metadata()?.len() != expected_len {
+			anyhow::bail!("{} has changed size since it was indexed", escaped(path));
+		}
+	}
+	let mut kept_buf = vec![0u8; COMPARE_BUF_SIZE];
+	let mut doomed_buf = vec![0u8; COMPARE_BUF_SIZE];
+	let mut total_read = 0u64;
+	loop {
+		let kept_read = read_full(&mut kept_file, &mut kept_buf)?;
+		let doomed_read = read_full(&mut doomed_file, &mut doomed_buf)?;
+		if kept_buf[..kept_read] != doomed_buf[..doomed_read] {
+			anyhow::bail!("{} and {} aren't identical", escaped(kept), escaped(doomed));
+		}
+		if kept_read == 0 {
+			break;
+		}
+		total_read += kept_read as u64;
+	}
+	if total_read != expected_len {
+		anyhow::bail!("{} has changed size since it was indexed", escaped(kept));
+	}
+	Ok(())
+}
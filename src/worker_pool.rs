@@ -0,0 +1,141 @@
+use std::{
+	any::Any,
+	panic::{self, AssertUnwindSafe},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc::sync_channel,
+		Condvar, Mutex, PoisonError,
+	},
+	thread,
+};
+
+/// Gets the message out of whatever a thread panicked with
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message.to_string()
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message.clone()
+	} else {
+		"unknown panic".into()
+	}
+}
+
+/// How far a group has got, shared between the workers
+struct GroupState<I> {
+	iter: I,
+	/// How many of its items can be worked on at once
+	jobs: usize,
+	/// How many of its items are being worked on right now
+	active: usize,
+	finished: bool,
+}
+
+/// Runs `callback` on every item using up to `jobs` threads, and hands each result to `on_result` on this thread as it
+/// comes in. Each group of items also has its own limit on how many of its items are worked on at once. A free worker
+/// takes its next item from whichever group has the fewest being worked on, so a slow group doesn't hold up the
+/// others, and a fast one can't take every worker. Only a few results can be waiting on `on_result` before the workers
+/// have to wait too.
+///
+/// If `on_result` returns an error or a worker panics, no more items are started and the error is returned. Every
+/// worker has been joined by the time this returns, nothing keeps running in the background.
+pub fn parallel_map<T: Send, U: Send, I: Iterator<Item = T> + Send>(
+	jobs: usize,
+	groups: impl IntoIterator<Item = (I, usize)>,
+	callback: impl Fn(T) -> U + Sync,
+	mut on_result: impl FnMut(U) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+	let groups = groups
+		.into_iter()
+		.map(|(iter, jobs)| GroupState {
+			iter,
+			jobs: jobs.max(1),
+			active: 0,
+			finished: false,
+		})
+		.collect::<Vec<_>>();
+	let total_jobs = groups.iter().map(|group| group.jobs).sum::<usize>().min(jobs.max(1));
+	let groups = Mutex::new(groups);
+	// Signalled whenever a group has room for another item, or everything is being cancelled
+	let group_freed = Condvar::new();
+	let cancelled = AtomicBool::new(false);
+	// The lock is held so a worker can't miss the signal between checking for cancellation and starting to wait
+	let cancel = || {
+		let _groups = groups.lock().unwrap_or_else(PoisonError::into_inner);
+		cancelled.store(true, Ordering::Relaxed);
+		group_freed.notify_all();
+	};
+	// Takes the next item from the least busy group with room for it, waiting for room if every group that has items
+	// left is already full. Returns `None` once everything has been started, or it's been cancelled.
+	let next_item = || {
+		let mut groups = groups.lock().unwrap_or_else(PoisonError::into_inner);
+		loop {
+			if cancelled.load(Ordering::Relaxed) {
+				return None;
+			}
+			let mut least_busy = None::<usize>;
+			let mut any_full = false;
+			for (group_num, group) in groups.iter().enumerate().filter(|(_, group)| !group.finished) {
+				if group.active >= group.jobs {
+					any_full = true;
+				} else if least_busy.is_none_or(|least_busy| group.active < groups[least_busy].active) {
+					least_busy = Some(group_num);
+				}
+			}
+			match least_busy {
+				Some(group_num) => {
+					let group = &mut groups[group_num];
+					match group.iter.next() {
+						Some(item) => {
+							group.active += 1;
+							return Some((group_num, item));
+						},
+						None => group.finished = true,
+					}
+				},
+				None if any_full => {
+					groups = group_freed.wait(groups).unwrap_or_else(PoisonError::into_inner);
+				},
+				None => return None,
+			}
+		}
+	};
+	let (result_send, result_recv) = sync_channel::<Result<U, String>>(total_jobs * 2);
+	thread::scope(|scope| {
+		for thread_num in 0..total_jobs {
+			let result_send = result_send.clone();
+			let (groups, group_freed, cancel, next_item, callback) =
+				(&groups, &group_freed, &cancel, &next_item, &callback);
+			thread::Builder::new()
+				.name(format!("Worker #{thread_num}"))
+				.spawn_scoped(scope, move || {
+					while let Some((group_num, item)) = next_item() {
+						let result = panic::catch_unwind(AssertUnwindSafe(|| callback(item)))
+							.map_err(|payload| panic_message(&*payload));
+						groups.lock().unwrap_or_else(PoisonError::into_inner)[group_num].active -= 1;
+						// Every waiting worker has to look again, the group might have run out instead of having room
+						group_freed.notify_all();
+						let panicked = result.is_err();
+						// If the receiver is gone, the consumer has already given up
+						if result_send.send(result).is_err() || panicked {
+							cancel();
+							break;
+						}
+					}
+				})
+				.unwrap();
+		}
+		drop(result_send);
+		// Taking the receiver by value means it's dropped on an early return, which unblocks any worker waiting to send
+		for result in result_recv {
+			let result = match result {
+				Ok(result) => on_result(result),
+				Err(message) => Err(anyhow::anyhow!("worker thread panicked: {message}")),
+			};
+			if result.is_err() {
+				cancel();
+				return result;
+			}
+		}
+		Ok(())
+	})
+}